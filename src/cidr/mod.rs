@@ -0,0 +1,142 @@
+//! IPv4 and IPv6 addresses with a prefix length, e.g. `10.0.0.5/24`
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use errors::GenericError;
+
+/// An address along with the prefix length of its subnet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    /// the address
+    pub addr: IpAddr,
+    /// length of the network prefix
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Builds a CIDR from an IPv4 address and a dotted netmask
+    pub fn from_netmask(ip: &str, netmask: &str) -> Result<Self, Box<Error>> {
+        let addr = Ipv4Addr::from_str(ip)?;
+        let mask = u32::from(Ipv4Addr::from_str(netmask)?);
+        let prefix = mask.count_ones();
+        if mask.checked_shl(prefix).unwrap_or(0) != 0 {
+            return Err(GenericError::bx("netmask is not contiguous"));
+        }
+        Ok(Cidr {
+            addr: IpAddr::V4(addr),
+            prefix: prefix as u8,
+        })
+    }
+
+    /// If this is an IPv4 address
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    /// Dotted netmask of an IPv4 CIDR
+    pub fn netmask(&self) -> Option<String> {
+        match self.addr {
+            IpAddr::V4(_) => {
+                let mask = (!0u32).checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                Some(Ipv4Addr::from(mask).to_string())
+            }
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// If `addr` is in the same subnet
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = (!0u32).checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let (a, b) = (a.octets(), b.octets());
+                let mut bits = self.prefix as usize;
+                for i in 0..16 {
+                    if bits == 0 {
+                        break;
+                    }
+                    let take = if bits >= 8 { 8 } else { bits };
+                    let mask = !0u8 << (8 - take);
+                    if a[i] & mask != b[i] & mask {
+                        return false;
+                    }
+                    bits -= take;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Box<Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr = IpAddr::from_str(parts.next().unwrap_or(""))?;
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>()?,
+            None => return Err(GenericError::bx("missing prefix length")),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(GenericError::bx("prefix length too long"));
+        }
+        Ok(Cidr {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cidr::*;
+
+    #[test]
+    fn parse() {
+        let v4: Cidr = "10.0.0.5/24".parse().unwrap();
+        assert_eq!(24, v4.prefix);
+        assert_eq!(Some(String::from("255.255.255.0")), v4.netmask());
+        let v6: Cidr = "fd00::5/64".parse().unwrap();
+        assert!(!v6.is_ipv4());
+        assert_eq!("fd00::5/64", v6.to_string());
+        assert!("10.0.0.5".parse::<Cidr>().is_err());
+        assert!("10.0.0.5/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.256/24".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn netmask() {
+        let cidr = Cidr::from_netmask("192.168.1.5", "255.255.254.0").unwrap();
+        assert_eq!("192.168.1.5/23", cidr.to_string());
+        assert!(Cidr::from_netmask("192.168.1.5", "255.0.255.0").is_err());
+        assert_eq!(0, Cidr::from_netmask("192.168.1.5", "0.0.0.0").unwrap().prefix);
+    }
+
+    #[test]
+    fn contains() {
+        let v4: Cidr = "10.0.0.5/24".parse().unwrap();
+        assert!(v4.contains(&"10.0.0.1".parse().unwrap()));
+        assert!(!v4.contains(&"10.0.1.1".parse().unwrap()));
+        assert!(!v4.contains(&"fd00::1".parse().unwrap()));
+        let v6: Cidr = "fd00::5/64".parse().unwrap();
+        assert!(v6.contains(&"fd00::1".parse().unwrap()));
+        assert!(!v6.contains(&"fd00:0:0:1::1".parse().unwrap()));
+        let odd: Cidr = "fd00::5/60".parse().unwrap();
+        assert!(odd.contains(&"fd00:0:0:f::1".parse().unwrap()));
+        assert!(!odd.contains(&"fd00:0:0:10::1".parse().unwrap()));
+    }
+}
@@ -1,13 +1,25 @@
 use std::io::Read;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
+use std::ops::{Deref, DerefMut};
 use std::collections::BTreeMap as Map;
+use std::rc::Rc;
+
+use executor::{self, Executor};
+#[cfg(test)]
+use executor::Simulator;
 
 
 use toml;
 extern crate slog;
 
 static CONFIG: &'static str = "/usr/local/etc/vmadm.toml";
+/// Where vmadm 0.1.0 kept its config file
+static LEGACY_CONFIG: &'static str = "/etc/vmadm.toml";
 
 /// Global settings
 #[derive(Debug, Deserialize, Serialize)]
@@ -22,17 +34,63 @@ pub struct Settings {
     pub image_dir: String,
     #[serde(default = "devfs_ruleset")]
     pub devfs_ruleset: u32,
-    pub networks: Map<String, String>,
+    /// Seconds a jail gets to shut down before it is killed
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+    /// Console shell per brand, overrides the brands default shell
+    #[serde(default)]
+    pub shells: Map<String, String>,
+    pub networks: Map<String, Network>,
+}
+
+/// A network jails can be attached to by its nic_tag, either the name of
+/// the bridge or a table with the bridge and the default MTU for NICs
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Network {
+    /// only the bridge
+    Bridge(String),
+    /// bridge along with the default MTU for NICs
+    Detailed {
+        /// the bridge epairs are added to
+        bridge: String,
+        /// default MTU for NICs on this network
+        mtu: Option<u32>,
+    },
+}
+
+impl Network {
+    /// The bridge epairs are added to
+    pub fn bridge(&self) -> &str {
+        match *self {
+            Network::Bridge(ref bridge) |
+            Network::Detailed { ref bridge, .. } => bridge.as_str(),
+        }
+    }
+
+    /// Default MTU for NICs on this network
+    pub fn mtu(&self) -> Option<u32> {
+        match *self {
+            Network::Bridge(_) => None,
+            Network::Detailed { mtu, .. } => mtu,
+        }
+    }
 }
 
 fn devfs_ruleset() -> u32 {
     4
 }
 
+fn default_stop_timeout() -> u64 {
+    60
+}
+
 /// Config object
 #[derive(Debug)]
 pub struct Config {
     pub settings: Settings,
+    /// Backend used to run system commands
+    pub executor: Rc<Executor>,
 }
 
 fn default_conf_dir() -> String {
@@ -50,14 +108,139 @@ fn default_repo() -> String {
 impl Config {
     /// Initializes config
     pub fn new() -> Result<Self, Box<Error>> {
-        debug!("Loading config file"; "config" => CONFIG);
-        let mut file = File::open(CONFIG)?;
+        let path = if !Path::new(CONFIG).exists() && Path::new(LEGACY_CONFIG).exists() {
+            warn!("Using legacy config file, run `vmadm db migrate` to move it";
+                  "config" => LEGACY_CONFIG);
+            LEGACY_CONFIG
+        } else {
+            CONFIG
+        };
+        debug!("Loading config file"; "config" => path);
+        let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents).expect(
             "Failed to read config file.",
         );
         let settings: Settings = toml::from_str(contents.as_str())?;
-        Ok(Config { settings: settings })
+        Ok(Config {
+            settings: settings,
+            executor: executor::default(),
+        })
+    }
+
+    /// Copies a 0.1.0 config file to its current location, the old file
+    /// is kept as a backup.
+    pub fn migrate(dry_run: bool) -> Result<Vec<String>, Box<Error>> {
+        let mut changes = Vec::new();
+        if !Path::new(CONFIG).exists() && Path::new(LEGACY_CONFIG).exists() {
+            changes.push(format!("copy {} -> {}", LEGACY_CONFIG, CONFIG));
+            if !dry_run {
+                fs::copy(LEGACY_CONFIG, CONFIG)?;
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// A temporary directory that is removed with everything in it when
+/// dropped, so failing tests do not leave it behind
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates a fresh directory below the systems temp dir
+    pub fn new() -> Self {
+        use std::env;
+        use uuid::Uuid;
+        let dir = env::temp_dir().join(Uuid::new_v4().hyphenated().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// Path of the directory
+    pub fn path(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Simulated config that owns its temporary config directory
+#[cfg(test)]
+pub struct Simulated {
+    config: Config,
+    _dir: TempDir,
+}
+
+#[cfg(test)]
+impl Simulated {
+    /// Config backed by a fresh simulator with a temporary config
+    /// directory that is removed when the config is dropped, the
+    /// simulator is returned to set up and inspect the host
+    pub fn new() -> (Self, Rc<Simulator>) {
+        let sim = Rc::new(Simulator::new());
+        let dir = TempDir::new();
+        let mut networks = Map::new();
+        networks.insert(String::from("admin"), Network::Bridge(String::from("bridge0")));
+        let config = Config {
+            settings: Settings {
+                pool: String::from("zroot/jails"),
+                repo: String::new(),
+                conf_dir: dir.path().to_string_lossy().into_owned(),
+                image_dir: String::new(),
+                devfs_ruleset: 4,
+                stop_timeout: 60,
+                shells: Map::new(),
+                networks: networks,
+            },
+            executor: sim.clone(),
+        };
+        let simulated = Simulated {
+            config: config,
+            _dir: dir,
+        };
+        (simulated, sim)
+    }
+}
+
+#[cfg(test)]
+impl Deref for Simulated {
+    type Target = Config;
+    fn deref(&self) -> &Config {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+impl DerefMut for Simulated {
+    fn deref_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::*;
+
+    #[test]
+    fn networks() {
+        let settings: Settings = toml::from_str(
+            r#"
+pool = "zroot/jails"
+[networks]
+admin = "bridge0"
+jumbo = { bridge = "bridge1", mtu = 9000 }
+"#,
+        ).unwrap();
+        let admin = &settings.networks["admin"];
+        assert_eq!(("bridge0", None), (admin.bridge(), admin.mtu()));
+        let jumbo = &settings.networks["jumbo"];
+        assert_eq!(("bridge1", Some(9000)), (jumbo.bridge(), jumbo.mtu()));
     }
-    // add code here
 }
@@ -110,7 +110,7 @@ pub struct ConflictError {
 impl ConflictError {
     /// Initialize a new conflict error
     pub fn new(uuid: &Uuid) -> ConflictError {
-        ConflictError { uuid: uuid.clone() }
+        ConflictError { uuid: *uuid }
     }
     /// Initialize a new conflict error in side a box
     pub fn bx(uuid: &Uuid) -> Box<Error> {
@@ -130,25 +130,37 @@ impl Error for ConflictError {
     }
 }
 
-/// Conflict error when a uuid is not found
+/// Error when a uuid or other identifier is not found
 #[derive(Debug)]
 pub struct NotFoundError {
-    uuid: Uuid,
+    what: String,
+    id: String,
 }
 impl NotFoundError {
-    /// Initialize a new conflict error
+    /// Initialize a new not found error
     pub fn new(uuid: &Uuid) -> NotFoundError {
-        NotFoundError { uuid: uuid.clone() }
+        NotFoundError {
+            what: String::from("UUID"),
+            id: uuid.hyphenated().to_string(),
+        }
     }
-    /// Initialize a new conflict error in side a box
+    /// Initialize a new not found error in side a box
     pub fn bx(uuid: &Uuid) -> Box<Error> {
         Box::new(NotFoundError::new(uuid))
     }
+    /// Initialize a new not found error for an identifier of a kind
+    /// (jail, image, ...) in side a box
+    pub fn bx_id(what: &str, id: &str) -> Box<Error> {
+        Box::new(NotFoundError {
+            what: String::from(what),
+            id: String::from(id),
+        })
+    }
 }
 
 impl fmt::Display for NotFoundError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "UUID not found: {}", self.uuid.hyphenated())
+        write!(f, "{} not found: {}", self.what, self.id)
     }
 }
 
@@ -157,3 +169,84 @@ impl Error for NotFoundError {
         "Not Found"
     }
 }
+
+/// Error when an identifier matches more then one thing
+#[derive(Debug)]
+pub struct AmbiguousError {
+    what: String,
+    id: String,
+    matches: Vec<String>,
+}
+impl AmbiguousError {
+    /// Initialize a new ambiguous error
+    pub fn new(what: &str, id: &str, matches: Vec<String>) -> AmbiguousError {
+        AmbiguousError {
+            what: String::from(what),
+            id: String::from(id),
+            matches: matches,
+        }
+    }
+    /// Initialize a new ambiguous error in side a box
+    pub fn bx(what: &str, id: &str, matches: Vec<String>) -> Box<Error> {
+        Box::new(AmbiguousError::new(what, id, matches))
+    }
+}
+
+impl fmt::Display for AmbiguousError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ambiguous {} {}, matches: {}",
+            self.what,
+            self.id,
+            self.matches.join(", ")
+        )
+    }
+}
+
+impl Error for AmbiguousError {
+    fn description(&self) -> &str {
+        "Ambiguous"
+    }
+}
+
+/// Error when the jail database is locked by another process
+#[derive(Debug)]
+pub struct LockedError {
+    path: String,
+    holder: String,
+}
+impl LockedError {
+    /// Initialize a new locked error
+    pub fn new(path: &str, holder: &str) -> LockedError {
+        LockedError {
+            path: String::from(path),
+            holder: String::from(holder),
+        }
+    }
+    /// Initialize a new locked error in side a box
+    pub fn bx(path: &str, holder: &str) -> Box<Error> {
+        Box::new(LockedError::new(path, holder))
+    }
+}
+
+impl fmt::Display for LockedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.holder.is_empty() {
+            write!(f, "Database is locked by another vmadm process: {}", self.path)
+        } else {
+            write!(
+                f,
+                "Database is locked by another vmadm process (pid {}): {}",
+                self.holder,
+                self.path
+            )
+        }
+    }
+}
+
+impl Error for LockedError {
+    fn description(&self) -> &str {
+        "Locked"
+    }
+}
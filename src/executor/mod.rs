@@ -0,0 +1,118 @@
+//! Abstraction over the system commands vmadm runs
+//!
+//! Every interaction with the host (jail, jls, rctl, mount, ifconfig, zfs,
+//! jexec) goes through an `Executor`. On FreeBSD this is the `System`
+//! backend that spawns the real commands, everywhere else the in memory
+//! `Simulator` is used so jail flows can be exercised on any host.
+
+use std::error::Error;
+use std::fmt::Debug;
+use std::io::{Read, Write, copy};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use errors::GenericError;
+
+#[cfg_attr(target_os = "freebsd", allow(dead_code))]
+mod simulator;
+pub use self::simulator::Simulator;
+
+/// Result of running a command
+#[derive(Debug, Clone)]
+pub struct Output {
+    /// Exit code of the command, -1 if it was killed by a signal
+    pub code: i32,
+    /// Standard output of the command
+    pub stdout: String,
+    /// Standard error of the command
+    pub stderr: String,
+}
+
+impl Output {
+    /// Successful output with the given stdout
+    pub fn ok(stdout: &str) -> Self {
+        Output {
+            code: 0,
+            stdout: String::from(stdout),
+            stderr: String::new(),
+        }
+    }
+    /// Failed output with the given stderr
+    pub fn fail(stderr: &str) -> Self {
+        Output {
+            code: 1,
+            stdout: String::new(),
+            stderr: String::from(stderr),
+        }
+    }
+    /// If the command exited successfully
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Backend used to run system commands
+pub trait Executor: Debug {
+    /// Runs a command and collects its output
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<Output, Box<Error>>;
+    /// Runs a command with `input` streamed to its stdin
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> Result<Output, Box<Error>>;
+    /// Runs a command attached to the current terminal and returns
+    /// its exit code
+    fn interactive(&self, cmd: &str, args: &[&str]) -> Result<i32, Box<Error>>;
+}
+
+/// Executor running the real system commands
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "freebsd"), allow(dead_code))]
+pub struct System;
+
+impl Executor for System {
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<Output, Box<Error>> {
+        let output = Command::new(cmd).args(args).output()?;
+        Ok(Output {
+            code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> Result<Output, Box<Error>> {
+        let mut child = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        {
+            let mut stdin = child.stdin.take().ok_or_else(
+                || GenericError::bx("failed to open stdin"),
+            )?;
+            copy(input, &mut stdin)?;
+            stdin.flush()?;
+        }
+        let output = child.wait_with_output()?;
+        Ok(Output {
+            code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    fn interactive(&self, cmd: &str, args: &[&str]) -> Result<i32, Box<Error>> {
+        let status = Command::new(cmd).args(args).status()?;
+        Ok(status.code().unwrap_or(-1))
+    }
+}
+
+/// The executor for the current platform
+#[cfg(target_os = "freebsd")]
+pub fn default() -> Rc<Executor> {
+    Rc::new(System)
+}
+
+/// The executor for the current platform
+#[cfg(not(target_os = "freebsd"))]
+pub fn default() -> Rc<Executor> {
+    Rc::new(Simulator::new())
+}
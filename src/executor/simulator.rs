@@ -0,0 +1,705 @@
+//! In memory simulation of the FreeBSD commands used by vmadm
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+
+use executor::{Executor, Output};
+
+/// A simulated epair interface
+#[derive(Debug, Clone)]
+struct Epair {
+    /// current name of the host side (a) end
+    a: String,
+    /// current name of the jail side (b) end
+    b: String,
+    /// bridge the a side was added to
+    bridge: Option<String>,
+    /// jail the b side was moved into
+    jail: Option<String>,
+}
+
+/// A simulated zfs dataset or snapshot
+#[derive(Debug, Clone)]
+struct Dataset {
+    origin: Option<String>,
+    /// tags of holds on a snapshot
+    holds: Vec<String>,
+    /// properties that were set, values are stored in bytes
+    properties: BTreeMap<String, String>,
+}
+
+impl Dataset {
+    fn new(origin: Option<String>) -> Self {
+        Dataset {
+            origin: origin,
+            holds: Vec::new(),
+            properties: BTreeMap::new(),
+        }
+    }
+    /// Numeric value of a property, 0 if it is not set
+    fn bytes(&self, property: &str) -> u64 {
+        self.properties
+            .get(property)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// Converts a zfs size like `5G` or `none` to bytes
+fn parse_size(size: &str) -> Option<u64> {
+    if size == "none" {
+        return Some(0);
+    }
+    let (num, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => size.split_at(pos),
+        None => (size, ""),
+    };
+    let factor: u64 = match unit {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    num.parse::<u64>().ok().map(|n| n * factor)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_jid: u64,
+    next_epair: u64,
+    jails: BTreeMap<String, u64>,
+    datasets: BTreeMap<String, Dataset>,
+    epairs: Vec<Epair>,
+    /// bridges and their MTU
+    bridges: BTreeMap<String, u32>,
+    mounts: BTreeMap<String, String>,
+    rctl: Vec<String>,
+    /// jails whose shutdown never finishes
+    stuck: Vec<String>,
+    /// bridges that refuse new members
+    full: Vec<String>,
+    /// jails nothing can be written to
+    read_only: Vec<String>,
+    /// shell scripts run with jexec
+    scripts: Vec<String>,
+}
+
+/// Executor that keeps track of jails, datasets, epairs, mounts and
+/// rctl rules in memory instead of running commands.
+#[derive(Debug, Default)]
+pub struct Simulator {
+    state: RefCell<State>,
+}
+
+impl Simulator {
+    /// Creates a new, empty simulator
+    pub fn new() -> Self {
+        let sim = Simulator::default();
+        sim.state.borrow_mut().next_jid = 1;
+        sim
+    }
+
+    /// Adds a bridge with the given MTU
+    #[cfg(test)]
+    pub fn add_bridge(&self, bridge: &str, mtu: u32) {
+        self.state.borrow_mut().bridges.insert(String::from(bridge), mtu);
+    }
+
+    /// Adds a dataset, used to seed images
+    pub fn add_dataset(&self, dataset: &str) {
+        self.state.borrow_mut().datasets.insert(
+            String::from(dataset),
+            Dataset::new(None),
+        );
+    }
+
+    /// Checks if a dataset or snapshot exists
+    #[cfg(test)]
+    pub fn has_dataset(&self, dataset: &str) -> bool {
+        self.state.borrow().datasets.contains_key(dataset)
+    }
+
+    /// Sets the space used by a dataset
+    #[cfg(test)]
+    pub fn set_used(&self, dataset: &str, used: u64) {
+        if let Some(ds) = self.state.borrow_mut().datasets.get_mut(dataset) {
+            ds.properties.insert(String::from("used"), used.to_string());
+        }
+    }
+
+    /// Returns the jid of a running jail
+    #[cfg(test)]
+    pub fn jid(&self, name: &str) -> Option<u64> {
+        self.state.borrow().jails.get(name).cloned()
+    }
+
+    /// Number of existing epairs
+    #[cfg(test)]
+    pub fn epair_count(&self) -> usize {
+        self.state.borrow().epairs.len()
+    }
+
+    /// Current mount points
+    #[cfg(test)]
+    pub fn mounts(&self) -> Vec<String> {
+        self.state.borrow().mounts.keys().cloned().collect()
+    }
+
+    /// Currently active rctl rules
+    #[cfg(test)]
+    pub fn rctl_rules(&self) -> Vec<String> {
+        self.state.borrow().rctl.clone()
+    }
+
+    /// Shell scripts run in jails so far
+    #[cfg(test)]
+    pub fn scripts(&self) -> Vec<String> {
+        self.state.borrow().scripts.clone()
+    }
+
+    /// Makes `rc.shutdown` in the given jail hang until it times out
+    #[cfg(test)]
+    pub fn hang_shutdown(&self, name: &str) {
+        self.state.borrow_mut().stuck.push(String::from(name));
+    }
+
+    /// Makes adding members to the given bridge fail
+    #[cfg(test)]
+    pub fn fail_bridge(&self, bridge: &str) {
+        self.state.borrow_mut().full.push(String::from(bridge));
+    }
+
+    /// Makes writing files in the given jail fail
+    #[cfg(test)]
+    pub fn read_only(&self, name: &str) {
+        self.state.borrow_mut().read_only.push(String::from(name));
+    }
+
+    fn is_read_only(&self, jid: &str) -> bool {
+        let state = self.state.borrow();
+        state.jails.iter().any(|(name, id)| {
+            (name.as_str() == jid || id.to_string() == jid) &&
+                state.read_only.iter().any(|r| name.starts_with(r.as_str()))
+        })
+    }
+
+    fn jexec(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match args.first() {
+            Some(jid) => {
+                if state.jails.iter().any(|(name, id)| name.as_str() == *jid || id.to_string() == *jid) {
+                    if let [_, "/bin/sh", "-c", script] = *args {
+                        state.scripts.push(String::from(script));
+                    }
+                    Output::ok("")
+                } else {
+                    Output::fail("jexec: jail not found")
+                }
+            }
+            None => Output::fail("jexec: missing jail"),
+        }
+    }
+
+    fn timeout(&self, args: &[&str]) -> Result<Output, Box<Error>> {
+        if args.len() < 2 {
+            return Ok(Output::fail("timeout: missing command"));
+        }
+        let stuck = {
+            let state = self.state.borrow();
+            args[1] == "jexec" &&
+                state.jails.iter().any(|(name, id)| {
+                    args.get(2) == Some(&id.to_string().as_str()) &&
+                        state.stuck.iter().any(|s| name.starts_with(s.as_str()))
+                })
+        };
+        if stuck {
+            return Ok(Output {
+                code: 124,
+                stdout: String::new(),
+                stderr: String::new(),
+            });
+        }
+        self.run(args[1], &args[2..])
+    }
+
+    fn jail(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        if args.first() == Some(&"-r") {
+            let name = match args.get(1) {
+                Some(name) => *name,
+                None => return Output::fail("jail: missing name"),
+            };
+            if !state.jails.contains_key(name) {
+                return Output::fail("jail: not found");
+            }
+            let mut child = String::from(name);
+            child.push('.');
+            state.jails.retain(
+                |j, _| j != name && !j.starts_with(child.as_str()),
+            );
+            for epair in state.epairs.iter_mut() {
+                if epair.jail.as_deref() == Some(name) {
+                    epair.jail = None;
+                }
+            }
+            return Output::ok("");
+        }
+        let name = match args.iter().find(|a| a.starts_with("name=")) {
+            Some(arg) => String::from(&arg[5..]),
+            None => return Output::fail("jail: missing name"),
+        };
+        if state.jails.contains_key(&name) {
+            return Output::fail("jail: already exists");
+        }
+        for arg in args.iter().filter(|a| a.starts_with("vnet.interface=")) {
+            let iface = &arg[15..];
+            match state.epairs.iter_mut().find(|e| e.b == iface) {
+                Some(epair) => epair.jail = Some(name.clone()),
+                None => return Output::fail("jail: unknown vnet interface"),
+            }
+        }
+        let jid = state.next_jid;
+        state.next_jid += 1;
+        state.jails.insert(name.clone(), jid);
+        // the outer jail starts the inner jail from exec.start
+        let nested = args.iter().any(|a| {
+            a.starts_with("exec.start=") && a.contains("jail -c")
+        });
+        if nested {
+            let mut inner = name.clone();
+            inner.push('.');
+            inner.push_str(name.as_str());
+            let inner_jid = state.next_jid;
+            state.next_jid += 1;
+            state.jails.insert(inner, inner_jid);
+        }
+        Output::ok(format!("{}\n", jid).as_str())
+    }
+
+    fn jls(&self, args: &[&str]) -> Output {
+        let state = self.state.borrow();
+        if let Some(pos) = args.iter().position(|a| *a == "-j") {
+            let jid = args.get(pos + 1).cloned().unwrap_or("");
+            return match state.jails.iter().find(|&(_, id)| id.to_string() == jid) {
+                Some((name, id)) => {
+                    Output::ok(format!("jid={} name={} persist\n", id, name).as_str())
+                }
+                None => Output::fail("jls: jail not found"),
+            };
+        }
+        let mut out = String::new();
+        for (name, jid) in state.jails.iter() {
+            out.push_str(format!("{} {}\n", jid, name).as_str());
+        }
+        Output::ok(out.as_str())
+    }
+
+    fn rctl(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match args.split_first() {
+            Some((&"-a", rules)) => {
+                for rule in rules {
+                    state.rctl.push(String::from(*rule));
+                }
+                Output::ok("")
+            }
+            Some((&"-u", filters)) => {
+                let mut out = String::new();
+                for rule in state.rctl.iter() {
+                    if filters.iter().any(|f| rule.starts_with(f)) {
+                        if let Some(resource) = rule.split(':').nth(2) {
+                            out.push_str(format!("{}=0\n", resource).as_str());
+                        }
+                    }
+                }
+                Output::ok(out.as_str())
+            }
+            Some((&"-r", filters)) => {
+                for filter in filters {
+                    state.rctl.retain(|r| !r.starts_with(filter));
+                }
+                Output::ok("")
+            }
+            None => {
+                let mut out = state.rctl.join("\n");
+                out.push('\n');
+                Output::ok(out.as_str())
+            }
+            _ => Output::fail("rctl: unsupported arguments"),
+        }
+    }
+
+    fn mount(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match args {
+            &["-p"] => {
+                let mut out = String::new();
+                for (target, fstype) in state.mounts.iter() {
+                    out.push_str(format!("{}\t{}\t{}\trw\t0 0\n", fstype, target, fstype).as_str());
+                }
+                Output::ok(out.as_str())
+            }
+            &["-t", fstype, _source, target] |
+            &["-t", fstype, "-o", _, _source, target] => {
+                if state.mounts.contains_key(target) {
+                    return Output::fail("mount: already mounted");
+                }
+                state.mounts.insert(String::from(target), String::from(fstype));
+                Output::ok("")
+            }
+            _ => Output::fail("mount: unsupported arguments"),
+        }
+    }
+
+    fn umount(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match args.last() {
+            Some(target) => {
+                match state.mounts.remove(*target) {
+                    Some(_) => Output::ok(""),
+                    None => Output::fail("umount: not a mount point"),
+                }
+            }
+            None => Output::fail("umount: missing target"),
+        }
+    }
+
+    fn ifconfig(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match *args {
+            ["epair", "create", ..] => {
+                let id = state.next_epair;
+                state.next_epair += 1;
+                let mut a = format!("epair{}a", id);
+                state.epairs.push(Epair {
+                    a: a.clone(),
+                    b: format!("epair{}b", id),
+                    bridge: None,
+                    jail: None,
+                });
+                a.push('\n');
+                Output::ok(a.as_str())
+            }
+            [bridge, "addm", _] if state.full.iter().any(|b| b == bridge) => {
+                Output::fail("ifconfig: BRDGADD: Device busy")
+            }
+            [bridge, "addm", member] => {
+                match state.epairs.iter_mut().find(|e| e.a == member) {
+                    Some(epair) => {
+                        epair.bridge = Some(String::from(bridge));
+                        Output::ok("")
+                    }
+                    None => Output::fail("ifconfig: unknown interface"),
+                }
+            }
+            [iface, "name", new_name] => {
+                match state.epairs.iter_mut().find(|e| e.a == iface) {
+                    Some(epair) => {
+                        epair.a = String::from(new_name);
+                        Output::ok(format!("{}\n", new_name).as_str())
+                    }
+                    None => Output::fail("ifconfig: unknown interface"),
+                }
+            }
+            [iface, "vnet", jid] => {
+                let jail = state
+                    .jails
+                    .iter()
+                    .find(|&(_, id)| id.to_string() == jid)
+                    .map(|(name, _)| name.clone());
+                match (jail, state.epairs.iter_mut().find(|e| e.b == iface)) {
+                    (Some(jail), Some(epair)) => {
+                        epair.jail = Some(jail);
+                        Output::ok("")
+                    }
+                    _ => Output::fail("ifconfig: unknown interface or jail"),
+                }
+            }
+            [iface, "destroy"] => {
+                let before = state.epairs.len();
+                state.epairs.retain(|e| e.a != iface && e.b != iface);
+                if state.epairs.len() < before {
+                    Output::ok("")
+                } else {
+                    Output::fail("ifconfig: unknown interface")
+                }
+            }
+            [bridge] if state.bridges.contains_key(bridge) => {
+                Output::ok(
+                    format!(
+                        "{}: flags=8843<UP,BROADCAST,RUNNING,SIMPLEX,MULTICAST> metric 0 mtu {}\n",
+                        bridge,
+                        state.bridges[bridge]
+                    ).as_str(),
+                )
+            }
+            [iface, ..] => {
+                if state.epairs.iter().any(|e| e.a == iface || e.b == iface) {
+                    Output::ok("")
+                } else {
+                    Output::fail("ifconfig: unknown interface")
+                }
+            }
+            _ => Output::fail("ifconfig: missing interface"),
+        }
+    }
+
+    fn zfs(&self, args: &[&str]) -> Output {
+        let mut state = self.state.borrow_mut();
+        match *args {
+            ["list", "-p", "-H", dataset] => {
+                match state.datasets.get(dataset) {
+                    Some(ds) => {
+                        let used = ds.bytes("used");
+                        let avail = ds.bytes("quota").saturating_sub(used);
+                        Output::ok(
+                            format!("{}\t{}\t{}\t{}\t/{}\n", dataset, used, avail, used, dataset)
+                                .as_str(),
+                        )
+                    }
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["get", "-p", "-H", "-o", "value", property, dataset] => {
+                match state.datasets.get(dataset) {
+                    Some(ds) => Output::ok(format!("{}\n", ds.bytes(property)).as_str()),
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["set", assignment, dataset] => {
+                let mut kv = assignment.splitn(2, '=');
+                let (property, value) = match (kv.next(), kv.next().and_then(parse_size)) {
+                    (Some(property), Some(value)) => (property, value),
+                    _ => return Output::fail("zfs: bad property value"),
+                };
+                match state.datasets.get_mut(dataset) {
+                    Some(ds) => {
+                        ds.properties.insert(String::from(property), value.to_string());
+                        Output::ok("")
+                    }
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["get", "-p", "-H", "origin", dataset] => {
+                match state.datasets.get(dataset) {
+                    Some(ds) => {
+                        let origin = ds.origin.clone().unwrap_or_else(|| String::from("-"));
+                        Output::ok(format!("{}\torigin\t{}\t-\n", dataset, origin).as_str())
+                    }
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["snapshot", snapshot] => {
+                let dataset = snapshot.split('@').next().unwrap_or("");
+                if !state.datasets.contains_key(dataset) {
+                    return Output::fail("dataset does not exist");
+                }
+                if state.datasets.contains_key(snapshot) {
+                    return Output::fail("dataset already exists");
+                }
+                state.datasets.insert(String::from(snapshot), Dataset::new(None));
+                Output::ok("")
+            }
+            ["clone", snapshot, dataset] => {
+                if !state.datasets.contains_key(snapshot) {
+                    return Output::fail("dataset does not exist");
+                }
+                if state.datasets.contains_key(dataset) {
+                    return Output::fail("dataset already exists");
+                }
+                state.datasets.insert(
+                    String::from(dataset),
+                    Dataset::new(Some(String::from(snapshot))),
+                );
+                Output::ok("")
+            }
+            ["hold", tag, snapshot] => {
+                match state.datasets.get_mut(snapshot) {
+                    Some(ref ds) if ds.holds.iter().any(|h| h == tag) => {
+                        Output::fail("tag already exists on this dataset")
+                    }
+                    Some(ds) => {
+                        ds.holds.push(String::from(tag));
+                        Output::ok("")
+                    }
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["release", tag, snapshot] => {
+                match state.datasets.get_mut(snapshot) {
+                    Some(ds) => {
+                        if !ds.holds.iter().any(|h| h == tag) {
+                            return Output::fail("no such tag on this dataset");
+                        }
+                        ds.holds.retain(|h| h != tag);
+                        Output::ok("")
+                    }
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            ["jail", jail, dataset] => {
+                if !state.jails.contains_key(jail) {
+                    return Output::fail("jail not found");
+                }
+                match state.datasets.get(dataset) {
+                    Some(ds) if ds.properties.get("jailed").map(|v| v.as_str()) == Some("on") => {
+                        Output::ok("")
+                    }
+                    Some(_) => Output::fail("dataset is not jailed"),
+                    None => Output::fail("dataset does not exist"),
+                }
+            }
+            _ if args.first() == Some(&"create") => {
+                let dataset = args.last().cloned().unwrap_or("");
+                let parent = dataset.rsplit_once('/').map(|x| x.0).unwrap_or("");
+                if state.datasets.contains_key(dataset) {
+                    return Output::fail("dataset already exists");
+                }
+                if !state.datasets.contains_key(parent) {
+                    return Output::fail("parent does not exist");
+                }
+                let mut ds = Dataset::new(None);
+                for (i, arg) in args.iter().enumerate() {
+                    if *arg != "-o" {
+                        continue;
+                    }
+                    let mut kv = args.get(i + 1).cloned().unwrap_or("").splitn(2, '=');
+                    if let (Some(property), Some(value)) = (kv.next(), kv.next()) {
+                        ds.properties.insert(String::from(property), String::from(value));
+                    }
+                }
+                state.datasets.insert(String::from(dataset), ds);
+                Output::ok("")
+            }
+            ["destroy", dataset] => {
+                if !state.datasets.contains_key(dataset) {
+                    return Output::fail("dataset does not exist");
+                }
+                if state.datasets.get(dataset).map(|ds| !ds.holds.is_empty()) == Some(true) {
+                    return Output::fail("dataset is busy");
+                }
+                let mut child_prefix = String::from(dataset);
+                child_prefix.push('/');
+                if state.datasets.keys().any(|name| name.starts_with(child_prefix.as_str())) {
+                    return Output::fail("dataset has children");
+                }
+                let mut snap_prefix = String::from(dataset);
+                snap_prefix.push('@');
+                let has_dependents = state.datasets.iter().any(|(name, ds)| {
+                    name.starts_with(snap_prefix.as_str()) ||
+                        ds.origin.as_deref() == Some(dataset)
+                });
+                if has_dependents {
+                    return Output::fail("dataset has dependent datasets");
+                }
+                state.datasets.remove(dataset);
+                Output::ok("")
+            }
+            _ => Output::fail("zfs: unsupported arguments"),
+        }
+    }
+}
+
+impl Executor for Simulator {
+    fn run(&self, cmd: &str, args: &[&str]) -> Result<Output, Box<Error>> {
+        debug!("simulating command"; "cmd" => cmd, "args" => args.join(" "));
+        let output = match cmd.rsplit('/').next().unwrap_or(cmd) {
+            "jail" => self.jail(args),
+            "jls" => self.jls(args),
+            "jexec" => self.jexec(args),
+            "timeout" => return self.timeout(args),
+            "rctl" => self.rctl(args),
+            "mount" => self.mount(args),
+            "umount" => self.umount(args),
+            "ifconfig" => self.ifconfig(args),
+            "zfs" => self.zfs(args),
+            "daemon" | "pkill" | "mkdir" => Output::ok(""),
+            _ => Output::fail("command not found"),
+        };
+        Ok(output)
+    }
+
+    fn pipe(&self, cmd: &str, args: &[&str], input: &mut Read) -> Result<Output, Box<Error>> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        match (cmd, args) {
+            ("zfs", &["receive", dataset]) => {
+                self.add_dataset(dataset);
+                Ok(Output::ok(""))
+            }
+            ("jexec", &[jid, ..]) if self.is_read_only(jid) => {
+                Ok(Output::fail("sh: cannot create: Read-only file system"))
+            }
+            _ => self.run(cmd, args),
+        }
+    }
+
+    fn interactive(&self, cmd: &str, args: &[&str]) -> Result<i32, Box<Error>> {
+        match cmd {
+            "jexec" => {
+                let args = if args.first() == Some(&"-U") {
+                    &args[2.min(args.len())..]
+                } else {
+                    args
+                };
+                Ok(self.jexec(args).code)
+            }
+            _ => Ok(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use executor::*;
+
+    #[test]
+    fn zfs_clone_and_destroy() {
+        let sim = Simulator::new();
+        sim.add_dataset("zroot/jails/image");
+        assert!(sim.run("zfs", &["snapshot", "zroot/jails/image@jail"]).unwrap().success());
+        assert!(sim.run("zfs", &["clone", "zroot/jails/image@jail", "zroot/jails/jail"])
+                .unwrap().success());
+        // the snapshot can't go while the clone exists
+        assert!(!sim.run("zfs", &["destroy", "zroot/jails/image@jail"]).unwrap().success());
+        let origin = sim.run("zfs", &["get", "-p", "-H", "origin", "zroot/jails/jail"]).unwrap();
+        assert_eq!("zroot/jails/jail\torigin\tzroot/jails/image@jail\t-\n", origin.stdout);
+        assert!(sim.run("zfs", &["destroy", "zroot/jails/jail"]).unwrap().success());
+        assert!(sim.run("zfs", &["destroy", "zroot/jails/image@jail"]).unwrap().success());
+        assert!(sim.has_dataset("zroot/jails/image"));
+        assert!(!sim.has_dataset("zroot/jails/jail"));
+    }
+
+    #[test]
+    fn nested_jail() {
+        let sim = Simulator::new();
+        let epair = sim.run("ifconfig", &["epair", "create", "up"]).unwrap();
+        assert_eq!("epair0a\n", epair.stdout);
+        let out = sim.run("jail", &["-i", "-c", "persist", "name=a", "vnet.interface=epair0b",
+                                    "exec.start=jail -c persist name=a"]).unwrap();
+        assert_eq!("1\n", out.stdout);
+        assert_eq!(Some(2), sim.jid("a.a"));
+        assert_eq!("1 a\n2 a.a\n", sim.run("jls", &["-q", "jid", "name"]).unwrap().stdout);
+        assert!(sim.run("jail", &["-r", "a"]).unwrap().success());
+        assert_eq!(None, sim.jid("a"));
+        assert_eq!(None, sim.jid("a.a"));
+    }
+
+    #[test]
+    fn rctl_rules() {
+        let sim = Simulator::new();
+        sim.run("rctl", &["-a", "jail:a:maxproc:deny=10", "jail:b:maxproc:deny=10"]).unwrap();
+        sim.run("rctl", &["-r", "jail:a"]).unwrap();
+        assert_eq!(vec![String::from("jail:b:maxproc:deny=10")], sim.rctl_rules());
+    }
+
+    #[test]
+    fn unknown_command() {
+        let sim = Simulator::new();
+        assert!(!sim.run("rm", &["-rf", "/"]).unwrap().success());
+    }
+}
@@ -0,0 +1,225 @@
+//! SmartOS style `field=value` filters on jail objects
+
+use std::error::Error;
+
+use regex::Regex;
+use serde_json::Value;
+
+use errors::GenericError;
+
+/// Comparison a filter performs
+#[derive(Debug)]
+enum Op {
+    /// `field=value`
+    Eq,
+    /// `field~=regex`
+    Match(Regex),
+    /// `field>value`
+    Gt,
+    /// `field>=value`
+    Ge,
+    /// `field<value`
+    Lt,
+    /// `field<=value`
+    Le,
+}
+
+/// A single filter like `nics.*.ip=10.0.0.1`
+#[derive(Debug)]
+pub struct Filter {
+    /// Dotted path of the field, `*` matches all elements of an array
+    pub field: String,
+    op: Op,
+    value: String,
+}
+
+impl Filter {
+    /// Parses a filter expression
+    pub fn parse(expr: &str) -> Result<Self, Box<Error>> {
+        let pos = expr.find(['=', '~', '<', '>'])
+            .ok_or_else(|| {
+                GenericError::bx(format!("Invalid filter: {}", expr).as_str())
+            })?;
+        let (field, rest) = expr.split_at(pos);
+        let (op, value) = if let Some(v) = rest.strip_prefix("~=") {
+            (Op::Match(Regex::new(v)?), v)
+        } else if let Some(v) = rest.strip_prefix(">=") {
+            (Op::Ge, v)
+        } else if let Some(v) = rest.strip_prefix("<=") {
+            (Op::Le, v)
+        } else if let Some(v) = rest.strip_prefix('=') {
+            (Op::Eq, v)
+        } else if let Some(v) = rest.strip_prefix('>') {
+            (Op::Gt, v)
+        } else if let Some(v) = rest.strip_prefix('<') {
+            (Op::Lt, v)
+        } else {
+            return Err(GenericError::bx(format!("Invalid filter: {}", expr).as_str()));
+        };
+        if field.is_empty() {
+            return Err(GenericError::bx(format!("Invalid filter: {}", expr).as_str()));
+        }
+        match op {
+            Op::Gt | Op::Ge | Op::Lt | Op::Le if value.parse::<f64>().is_err() => {
+                return Err(GenericError::bx(
+                    format!("Numeric comparison with a non number: {}", expr).as_str(),
+                ));
+            }
+            _ => (),
+        }
+        Ok(Filter {
+            field: String::from(field),
+            op: op,
+            value: String::from(value),
+        })
+    }
+
+    /// Parses a `key=value` tag selector into a filter on `tags.<key>`
+    pub fn tag(expr: &str) -> Result<Self, Box<Error>> {
+        match expr.find('=') {
+            Some(pos) if pos > 0 => Filter::parse(format!("tags.{}", expr).as_str()),
+            _ => Err(GenericError::bx(format!("Invalid tag, expected key=value: {}", expr).as_str())),
+        }
+    }
+
+    /// Checks if any value at the filters path matches
+    pub fn matches(&self, obj: &Value) -> bool {
+        lookup(obj, self.field.as_str()).iter().any(
+            |v| self.matches_value(v),
+        )
+    }
+
+    fn matches_value(&self, value: &Value) -> bool {
+        let s = match to_string(value) {
+            Some(s) => s,
+            None => return false,
+        };
+        match self.op {
+            Op::Eq => s == self.value,
+            Op::Match(ref re) => re.is_match(s.as_str()),
+            _ => {
+                let (a, b) = match (s.parse::<f64>(), self.value.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => (a, b),
+                    _ => return false,
+                };
+                match self.op {
+                    Op::Gt => a > b,
+                    Op::Ge => a >= b,
+                    Op::Lt => a < b,
+                    Op::Le => a <= b,
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// Resolves a dotted path in a JSON value, `*` matches every element of
+/// an array or object and numbers index into arrays.
+pub fn lookup<'v>(value: &'v Value, path: &str) -> Vec<&'v Value> {
+    let mut current = vec![value];
+    for key in path.split('.') {
+        let mut next = Vec::new();
+        for v in current {
+            match (v, key) {
+                (Value::Array(a), "*") => next.extend(a.iter()),
+                (Value::Object(o), "*") => next.extend(o.values()),
+                (Value::Array(a), _) => {
+                    if let Some(e) = key.parse::<usize>().ok().and_then(|i| a.get(i)) {
+                        next.push(e)
+                    }
+                }
+                (Value::Object(o), _) => {
+                    if let Some(e) = o.get(key) {
+                        next.push(e)
+                    }
+                }
+                _ => (),
+            }
+        }
+        current = next;
+    }
+    current
+}
+
+/// String form of a scalar JSON value
+pub fn to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Number(ref n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Null => Some(String::from("null")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use filter::*;
+    use serde_json;
+
+    fn obj() -> Value {
+        serde_json::from_str(
+            r#"{
+            "alias": "web01",
+            "max_physical_memory": 1024,
+            "autoboot": true,
+            "tags": {"role": "web", "shard": 3},
+            "nics": [
+                {"interface": "net0", "ip": "10.0.0.5"},
+                {"interface": "net1", "ip": "192.168.1.5"}
+            ]
+        }"#,
+        ).unwrap()
+    }
+
+    #[test]
+    fn eq() {
+        assert!(Filter::parse("alias=web01").unwrap().matches(&obj()));
+        assert!(!Filter::parse("alias=web02").unwrap().matches(&obj()));
+        assert!(Filter::parse("autoboot=true").unwrap().matches(&obj()));
+        assert!(Filter::parse("max_physical_memory=1024").unwrap().matches(&obj()));
+    }
+
+    #[test]
+    fn nested() {
+        assert!(Filter::parse("nics.*.ip=192.168.1.5").unwrap().matches(&obj()));
+        assert!(Filter::parse("nics.0.ip=10.0.0.5").unwrap().matches(&obj()));
+        assert!(!Filter::parse("nics.0.ip=192.168.1.5").unwrap().matches(&obj()));
+        assert!(!Filter::parse("nics.*.mtu=1500").unwrap().matches(&obj()));
+    }
+
+    #[test]
+    fn regex() {
+        assert!(Filter::parse("alias~=^web").unwrap().matches(&obj()));
+        assert!(Filter::parse("nics.*.ip~=^10\\.").unwrap().matches(&obj()));
+        assert!(!Filter::parse("alias~=^db").unwrap().matches(&obj()));
+    }
+
+    #[test]
+    fn numeric() {
+        assert!(Filter::parse("max_physical_memory>512").unwrap().matches(&obj()));
+        assert!(Filter::parse("max_physical_memory>=1024").unwrap().matches(&obj()));
+        assert!(!Filter::parse("max_physical_memory<1024").unwrap().matches(&obj()));
+        assert!(Filter::parse("max_physical_memory<=1024").unwrap().matches(&obj()));
+        assert!(!Filter::parse("alias>1").unwrap().matches(&obj()));
+    }
+
+    #[test]
+    fn tags() {
+        assert!(Filter::tag("role=web").unwrap().matches(&obj()));
+        assert!(Filter::tag("shard=3").unwrap().matches(&obj()));
+        assert!(!Filter::tag("role=db").unwrap().matches(&obj()));
+        assert!(!Filter::tag("missing=web").unwrap().matches(&obj()));
+        assert!(Filter::tag("role").is_err());
+        assert!(Filter::tag("=web").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Filter::parse("alias").is_err());
+        assert!(Filter::parse("=web01").is_err());
+        assert!(Filter::parse("max_physical_memory>lots").is_err());
+        assert!(Filter::parse("alias~=(").is_err());
+    }
+}
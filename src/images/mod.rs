@@ -6,6 +6,7 @@ use std::io::copy;
 
 use config::Config;
 use errors::GenericError;
+use jdb;
 use zfs;
 
 use reqwest;
@@ -33,7 +34,7 @@ impl ManifestWrapper {
         R: Read,
     {
         let manifest: ManifestWrapper = serde_json::from_reader(reader)?;
-        return Ok(manifest);
+        Ok(manifest)
     }
 }
 
@@ -68,7 +69,7 @@ impl Image {
         R: Read,
     {
         let image: Image = serde_json::from_reader(reader)?;
-        return Ok(image);
+        Ok(image)
     }
 
     pub fn list_from_reader<R>(reader: R) -> Result<Vec<Self>, Box<Error>>
@@ -76,7 +77,7 @@ impl Image {
         R: Read,
     {
         let images: Vec<Image> = serde_json::from_reader(reader)?;
-        return Ok(images);
+        Ok(images)
     }
     fn print(&self, table: &mut Table, parsable: bool) {
 
@@ -114,15 +115,7 @@ fn print_images(images: Vec<Image>, headerless: bool, parsable: bool) {
     table.set_format(*format::consts::FORMAT_CLEAN);
     if !headerless {
         if parsable {
-            println!(
-                "{}:{}:{}:{}:{}:{}",
-                "UUID",
-                "NAME",
-                "VERSION",
-                "OS",
-                "TYPE",
-                "PUB"
-            );
+            println!("UUID:NAME:VERSION:OS:TYPE:PUB");
         } else {
             table.add_row(row!["UUID", "NAME", "VERSION", "OS", "TYPE", "PUB"]);
         }
@@ -146,6 +139,50 @@ pub fn list(config: &Config) -> Result<i32, Box<Error>> {
     print_images(images, false, false);
     Ok(0)
 }
+/// Name of an installed image, `None` if there is no manifest for it
+pub fn name(config: &Config, uuid: &Uuid) -> Option<String> {
+    let entries = match fs::read_dir(config.settings.image_dir.clone()) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    for entry in entries {
+        let manifest = entry.ok()
+            .and_then(|e| File::open(e.path()).ok())
+            .and_then(|f| ManifestWrapper::from_reader(f).ok());
+        if let Some(manifest) = manifest {
+            if manifest.manifest.uuid == *uuid {
+                return Some(manifest.manifest.name);
+            }
+        }
+    }
+    None
+}
+
+/// Resolves a UUID, UUID prefix or name of an installed image
+pub fn resolve_installed(config: &Config, id: &str) -> Result<Uuid, Box<Error>> {
+    let mut candidates = Vec::new();
+    for entry in fs::read_dir(config.settings.image_dir.clone())? {
+        let image_file = File::open(entry?.path())?;
+        let manifest = ManifestWrapper::from_reader(image_file)?;
+        candidates.push((manifest.manifest.uuid, manifest.manifest.name));
+    }
+    jdb::resolve_id("image", id, &candidates)
+}
+
+/// Resolves a UUID, UUID prefix or name of an image in the repository,
+/// a full UUID is taken as is without asking the repository.
+pub fn resolve_avail(config: &Config, id: &str) -> Result<Uuid, Box<Error>> {
+    if let Ok(uuid) = Uuid::parse_str(id) {
+        return Ok(uuid);
+    }
+    let resp = reqwest::get(config.settings.repo.as_str())?;
+    let candidates: Vec<(Uuid, String)> = Image::list_from_reader(resp)?
+        .into_iter()
+        .map(|image| (image.uuid, image.name))
+        .collect();
+    jdb::resolve_id("image", id, &candidates)
+}
+
 pub fn avail(config: &Config) -> Result<i32, Box<Error>> {
     debug!("Listing images"; "repo" => config.settings.repo.clone());
     let resp = reqwest::get(config.settings.repo.as_str())?;
@@ -196,7 +233,7 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     url.push('/');
     url.push_str(uuid_str.as_str());
 
-    if zfs::is_present(dataset.as_str()) {
+    if zfs::is_present(config, dataset.as_str()) {
             return Err(GenericError::bx("Dataset already present"));
     };
 
@@ -211,7 +248,7 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
             let mut origin_dataset = config.settings.pool.clone();
             origin_dataset.push('/');
             origin_dataset.push_str(origin.hyphenated().to_string().as_str());
-            if ! zfs::is_present(origin_dataset.as_str()) {
+            if ! zfs::is_present(config, origin_dataset.as_str()) {
                 import(config, origin)?;
             }
         }
@@ -227,11 +264,11 @@ pub fn import(config: &Config, uuid: Uuid) -> Result<i32, Box<Error>> {
     match file_info.compression.as_str() {
         "bzip2" => {
             let mut decompressor = BzDecoder::new(out);
-            zfs::receive(dataset.as_str(), &mut decompressor)?;
+            zfs::receive(config, dataset.as_str(), &mut decompressor)?;
         }
         "gzip" => {
             let mut decompressor = GzDecoder::new(out)?;
-            zfs::receive(dataset.as_str(), &mut decompressor)?;
+            zfs::receive(config, dataset.as_str(), &mut decompressor)?;
         }
         compression => {
             println!("Encountered {} compression", compression);
@@ -1,17 +1,18 @@
 //! Jail Configuration
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-#[cfg(target_os = "freebsd")]
-use std::process::Command;
-#[cfg(target_os = "freebsd")]
-use errors::GenericError;
 
-use errors::{ValidationError, ValidationErrors};
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use errors::{GenericError, ValidationError, ValidationErrors};
 use config::Config;
+use cidr::Cidr;
 
-use serde_json;
+use serde_json::{self, Value};
 use uuid::Uuid;
 use regex::Regex;
 use rand::{thread_rng, Rng};
@@ -30,12 +31,23 @@ pub struct NIC {
     pub vlan: Option<u16>,
     /// The nic_tag for the nic to uise
     pub nic_tag: String,
-    /// The IP for the nic
+    /// The IPv4 address of the nic or `dhcp`, the first IPv4 address of
+    /// `ips`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub ip: String,
-    /// The netmask for the nic
+    /// The netmask for `ip`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub netmask: String,
-    /// The gateway for the nic
+    /// The IPv4 gateway for the nic, the first IPv4 gateway of `gateways`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub gateway: String,
+    /// IPv4 and IPv6 addresses of the nic in CIDR notation, `dhcp` or
+    /// `addrconf`
+    #[serde(default)]
+    pub ips: Vec<String>,
+    /// IPv4 and IPv6 gateways of the nic
+    #[serde(default)]
+    pub gateways: Vec<String>,
     #[serde(default = "dflt_false")]
     /// If this nic is the primary interface or not
     pub primary: bool,
@@ -53,17 +65,24 @@ impl PartialEq for NIC {
             self.mac == other.mac &&
             self.vlan == other.vlan &&
             self.nic_tag == other.nic_tag &&
-            self.ip == self.ip &&
+            self.ip == other.ip &&
             self.netmask == other.netmask &&
             self.gateway == other.gateway &&
+            self.ips == other.ips &&
+            self.gateways == other.gateways &&
             self.primary == other.primary &&
             self.mtu == other.mtu &&
             self.network_uuid == other.network_uuid
     }
 }
 
-#[cfg(target_os = "freebsd")]
 static IFCONFIG: &'static str = "/sbin/ifconfig";
+/// address keyword to get an IPv4 address via DHCP
+pub static DHCP: &'static str = "dhcp";
+/// address keyword to configure IPv6 via router advertisements
+pub static ADDRCONF: &'static str = "addrconf";
+/// customer metadata key of the script run when the jail boots
+pub static USER_SCRIPT: &'static str = "user-script";
 
 /// Interface after creating
 #[derive(Debug, Clone)]
@@ -77,117 +96,371 @@ pub struct IFace {
 }
 
 impl NIC {
+    /// Fills in `ips`/`gateways` from `ip`/`netmask`/`gateway` or the
+    /// other way around, whichever is missing
+    pub fn normalize(&mut self) {
+        if self.ips.is_empty() && self.gateways.is_empty() {
+            self.legacy_to_ips();
+        }
+        if self.ip.is_empty() && self.gateway.is_empty() {
+            self.ips_to_legacy();
+        }
+    }
+
+    /// Replaces the first IPv4 address and gateway in `ips` and
+    /// `gateways` with `ip`/`netmask` and `gateway`
+    pub fn legacy_to_ips(&mut self) {
+        let is_v4 = |ip: &String| {
+            ip == DHCP || ip.split('/').next().map(|ip| Ipv4Addr::from_str(ip).is_ok()) == Some(true)
+        };
+        let ip = if self.ip == DHCP {
+            Some(String::from(DHCP))
+        } else {
+            Cidr::from_netmask(self.ip.as_str(), self.netmask.as_str())
+                .ok()
+                .map(|cidr| cidr.to_string())
+        };
+        if let Some(ip) = ip {
+            match self.ips.iter().position(&is_v4) {
+                Some(i) => self.ips[i] = ip,
+                None => self.ips.insert(0, ip),
+            }
+        }
+        if !self.gateway.is_empty() {
+            match self.gateways.iter().position(&is_v4) {
+                Some(i) => self.gateways[i] = self.gateway.clone(),
+                None => self.gateways.insert(0, self.gateway.clone()),
+            }
+        }
+    }
+
+    /// Sets `ip`/`netmask` and `gateway` from the first IPv4 address and
+    /// gateway in `ips` and `gateways`
+    pub fn ips_to_legacy(&mut self) {
+        let cidr = self.cidrs().into_iter().find(|c| c.is_ipv4());
+        self.ip = match cidr {
+            Some(cidr) => cidr.addr.to_string(),
+            None if self.dhcp() => String::from(DHCP),
+            None => String::new(),
+        };
+        self.netmask = cidr.and_then(|c| c.netmask()).unwrap_or_default();
+        self.gateway = self.gateways
+            .iter()
+            .find(|gw| Ipv4Addr::from_str(gw).is_ok())
+            .cloned()
+            .unwrap_or_default();
+    }
+
+    /// If the nic gets its IPv4 address via DHCP
+    pub fn dhcp(&self) -> bool {
+        self.ips.iter().any(|ip| ip == DHCP)
+    }
+
+    /// If the nic configures IPv6 via router advertisements
+    pub fn addrconf(&self) -> bool {
+        self.ips.iter().any(|ip| ip == ADDRCONF)
+    }
+
+    /// The valid static addresses of `ips`
+    pub fn cidrs(&self) -> Vec<Cidr> {
+        self.ips.iter().filter_map(|ip| ip.parse().ok()).collect()
+    }
+
+    /// checks the nic for errors, `path` is prefixed to the fields
+    pub fn errors(&self, config: &Config, path: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let field = |name: &str| format!("{}.{}", path, name);
+        if !INTERFACE_RE.is_match(self.interface.as_str()) {
+            errors.push(ValidationError::new(field("interface").as_str(), "Invalid interface name"))
+        }
+        if !self.ip.is_empty() && self.ip != DHCP {
+            if !is_ip(self.ip.as_str()) {
+                errors.push(ValidationError::new(field("ip").as_str(), "Invalid ip"))
+            }
+            if Cidr::from_netmask("0.0.0.0", self.netmask.as_str()).is_err() {
+                errors.push(ValidationError::new(field("netmask").as_str(), "Invalid netmask"))
+            }
+        }
+        if !self.gateway.is_empty() && !is_ip(self.gateway.as_str()) {
+            errors.push(ValidationError::new(field("gateway").as_str(), "Invalid gateway"))
+        }
+        if self.ips.is_empty() {
+            errors.push(ValidationError::new(field("ips").as_str(), "No address"))
+        }
+        for (i, ip) in self.ips.iter().enumerate() {
+            if ip != DHCP && ip != ADDRCONF && ip.parse::<Cidr>().is_err() {
+                errors.push(ValidationError::new(
+                    format!("{}.ips[{}]", path, i).as_str(),
+                    "Invalid address",
+                ))
+            }
+        }
+        for (i, gateway) in self.gateways.iter().enumerate() {
+            if IpAddr::from_str(gateway).is_err() {
+                errors.push(ValidationError::new(
+                    format!("{}.gateways[{}]", path, i).as_str(),
+                    "Invalid gateway",
+                ))
+            }
+        }
+        if !MAC_RE.is_match(self.mac.as_str()) {
+            errors.push(ValidationError::new(field("mac").as_str(), "Invalid mac"))
+        }
+        if !config.settings.networks.contains_key(&self.nic_tag) {
+            errors.push(ValidationError::new(field("nic_tag").as_str(), "Unknown nic_tag"))
+        }
+        errors
+    }
+
+    /// checks a nic given on create or update, on top of `errors` the
+    /// gateways have to be reachable from the addresses and the MTU can
+    /// not be larger than the one of the bridge
+    pub fn input_errors(&self, config: &Config, path: &str) -> Vec<ValidationError> {
+        let mut errors = self.errors(config, path);
+        let cidrs = self.cidrs();
+        for (i, gateway) in self.gateways.iter().enumerate() {
+            if let Ok(gateway) = IpAddr::from_str(gateway) {
+                let dynamic = if gateway.is_ipv4() {
+                    self.dhcp()
+                } else {
+                    self.addrconf()
+                };
+                if !dynamic && !cidrs.iter().any(|c| c.contains(&gateway)) {
+                    errors.push(ValidationError::new(
+                        format!("{}.gateways[{}]", path, i).as_str(),
+                        "Gateway not reachable",
+                    ))
+                }
+            }
+        }
+        if let Some(network) = config.settings.networks.get(&self.nic_tag) {
+            let mtu = self.mtu.or(network.mtu());
+            if let (Some(mtu), Some(max)) = (mtu, bridge_mtu(config, network.bridge())) {
+                if mtu > max {
+                    errors.push(ValidationError::new(
+                        format!("{}.mtu", path).as_str(),
+                        format!("MTU larger than the MTU of {} ({})", network.bridge(), max)
+                            .as_str(),
+                    ))
+                }
+            }
+        }
+        errors
+    }
+
+    /// The MTU of the nic, if it is not set the default of its network
+    pub fn effective_mtu(&self, config: &Config) -> Option<u32> {
+        self.mtu.or_else(|| {
+            config.settings.networks.get(&self.nic_tag).and_then(
+                |network| network.mtu(),
+            )
+        })
+    }
+
+    /// Commands to set the addresses of the interface and, for the
+    /// primary nic, the default routes
+    pub fn address_script(&self) -> String {
+        let mut script = String::new();
+        let cidrs = self.cidrs();
+        if self.addrconf() {
+            script.push_str(
+                format!("/sbin/ifconfig {} inet6 -ifdisabled accept_rtadv; ", self.interface)
+                    .as_str(),
+            );
+        } else if cidrs.iter().any(|c| !c.is_ipv4()) {
+            script.push_str(format!("/sbin/ifconfig {} inet6 -ifdisabled; ", self.interface).as_str());
+        }
+        let (mut v4, mut v6) = (false, false);
+        for cidr in cidrs {
+            let (family, first) = if cidr.is_ipv4() {
+                ("inet", !v4)
+            } else {
+                ("inet6", !v6)
+            };
+            let alias = if first { "" } else { " alias" };
+            script.push_str(
+                format!("/sbin/ifconfig {} {} {}{}; ", self.interface, family, cidr, alias)
+                    .as_str(),
+            );
+            if cidr.is_ipv4() {
+                v4 = true
+            } else {
+                v6 = true
+            }
+        }
+        if self.primary {
+            let gateways: Vec<IpAddr> = self.gateways.iter().filter_map(|gw| gw.parse().ok()).collect();
+            if let Some(gw) = gateways.iter().find(|gw| gw.is_ipv4()) {
+                script.push_str(format!("/sbin/route add default -gateway {}; ", gw).as_str());
+            }
+            if let Some(gw) = gateways.iter().find(|gw| gw.is_ipv6()) {
+                script.push_str(format!("/sbin/route add -inet6 default {}; ", gw).as_str());
+            }
+        }
+        script
+    }
+
+    /// Commands to start DHCP and router solicitation, these have to run
+    /// after the addresses of all interfaces are set
+    pub fn autoconf_script(&self) -> String {
+        let mut script = String::new();
+        if self.dhcp() {
+            script.push_str(format!("/sbin/dhclient -b {}; ", self.interface).as_str());
+        }
+        if self.addrconf() {
+            script.push_str(format!("/sbin/rtsol {}; ", self.interface).as_str());
+        }
+        script
+    }
+
     /// Creates the related interface
-    #[cfg(target_os = "freebsd")]
     pub fn get_iface(&self, config: &Config, uuid: &Uuid) -> Result<IFace, Box<Error>> {
-        let output = Command::new(IFCONFIG)
-            .args(&["epair", "create", "up"])
-            .output()
-            .expect("failed ifconfig");
-        if !output.status.success() {
+        let bridge = match config.settings.networks.get(&self.nic_tag) {
+            Some(network) => network.bridge(),
+            None => return Err(GenericError::bx("bridge not configured")),
+        };
+        let mtu = self.effective_mtu(config);
+        // the bridge may have changed since the nic was validated
+        if let (Some(mtu), Some(max)) = (mtu, bridge_mtu(config, bridge)) {
+            if mtu > max {
+                warn!("NIC MTU is larger than the bridge MTU"; "vm" => uuid.hyphenated().to_string(),
+                      "interface" => self.interface.clone(), "mtu" => mtu, "bridge" => bridge, "bridge_mtu" => max);
+            }
+        }
+        let mtu = mtu.map(|mtu| mtu.to_string());
+        let output = config.executor.run(IFCONFIG, &["epair", "create", "up"])?;
+        if !output.success() {
             return Err(GenericError::bx("could not create interface"));
         }
-        let reply = String::from_utf8_lossy(&output.stdout);
-        let epaira = reply.trim();
+        let epaira = output.stdout.trim();
         let mut epair = String::from(epaira);
 
         epair.pop();
-        match config.settings.networks.get(&self.nic_tag) {
-            Some(bridge) => {
-
-                let output = Command::new(IFCONFIG)
-                    .args(&[bridge.as_str(), "addm", epaira])
-                    .output()
-                    .expect("failed ifconfig");
+        // do not leave the epair behind when setting it up fails
+        let script = match self.setup_epair(config, uuid, bridge, epair.as_str(), mtu) {
+            Ok(script) => script,
+            Err(e) => {
+                let _ = config.executor.run(IFCONFIG, &[epaira, "destroy"]);
+                return Err(e);
+            }
+        };
+        Ok(IFace {
+            iface: self.interface.clone(),
+            epair: epair,
+            start_script: script,
+        })
+    }
 
-                if !output.status.success() {
-                    return Err(GenericError::bx("could not add epair to bridge"));
+    /// Sets the MTU of a freshly created epair, adds it to the bridge and
+    /// returns the script to configure it inside the jail
+    fn setup_epair(
+        &self,
+        config: &Config,
+        uuid: &Uuid,
+        bridge: &str,
+        epair: &str,
+        mtu: Option<String>,
+    ) -> Result<String, Box<Error>> {
+        let epaira = format!("{}a", epair);
+        if let Some(ref mtu) = mtu {
+            let epairb = format!("{}b", epair);
+            for end in &[epaira.as_str(), epairb.as_str()] {
+                let output = config.executor.run(IFCONFIG, &[end, "mtu", mtu.as_str()])?;
+                if !output.success() {
+                    return Err(GenericError::bx("could not set mtu"));
                 }
             }
-            None => return Err(GenericError::bx("bridge not configured")),
+        }
+        let output = config.executor.run(IFCONFIG, &[bridge, "addm", epaira.as_str()])?;
+        if !output.success() {
+            return Err(GenericError::bx("could not add epair to bridge"));
         }
 
-        let mut script = if self.vlan.is_some() {
+        let mut script = if let Some(vlan) = self.vlan {
             // This may seem stupid but freebsd can't create a vlan interface
             // that is not named vlan<X> or <interface>.<X>
             // however once created it happiely renames it ...
             format!(
                 "/sbin/ifconfig {epair}b.{vlan} create vlan {vlan} vlandev {epair}p; \
-                /sbin/ifconfig {epair}b.{vlan} name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} {mask}; ",
+                /sbin/ifconfig {epair}b.{vlan} name {iface}; ",
                 epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
                 iface = self.interface,
-                vlan = self.vlan.unwrap()
+                vlan = vlan
             )
         } else {
             format!(
-                "/sbin/ifconfig {epair}b name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} {mask}; ",
+                "/sbin/ifconfig {epair}b name {iface}; ",
                 epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
                 iface = self.interface
             )
         };
-        if self.primary {
-            let route = format!("/sbin/route add default -gateway {}; ", self.gateway);
-            script.push_str(route.as_str())
+        if let (Some(mtu), Some(_)) = (mtu, self.vlan) {
+            script.push_str(format!("/sbin/ifconfig {} mtu {}; ", self.interface, mtu).as_str());
         }
+        script.push_str(self.address_script().as_str());
         let mut desc = String::from("VNic from jail ");
         desc.push_str(uuid.hyphenated().to_string().as_str());
-        let output = Command::new(IFCONFIG)
-            .args(&[epaira, "description", desc.as_str()])
-            .output()
-            .expect("failed to add descirption");
-        if !output.status.success() {
+        let output = config.executor.run(IFCONFIG, &[epaira.as_str(), "description", desc.as_str()])?;
+        if !output.success() {
             return Err(GenericError::bx("could not set description"));
         }
-        Ok(IFace {
-            iface: self.interface.clone(),
-            epair: epair,
-            start_script: script,
-        })
+        Ok(script)
     }
-    /// Creates the related interface
-    #[cfg(not(target_os = "freebsd"))]
-    pub fn get_iface(&self, _config: &Config, _uuid: &Uuid) -> Result<IFace, Box<Error>> {
-        let epair = "epair0";
-        let script = if self.vlan.is_some() {
-            // This may seem stupid but freebsd can't create a vlan interface
-            // that is not named vlan<X> or <interface>.<X>
-            // however once created it happiely renames it ...
-            format!(
-                "/sbin/ifconfig {epair}b name {iface}p; \
-                /sbin/ifconfig {iface}p.{vlan} create vlan {vlan} vlandev {iface}p; \
-                /sbin/ifconfig {iface}p.{vlan} name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} {mask}; ",
-                epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
-                iface = self.interface,
-                vlan = self.vlan.unwrap()
-            )
-        } else {
-            format!(
-                "/sbin/ifconfig {epair}b name {iface}; \
-                /sbin/ifconfig {iface} inet {ip} {mask}; ",
-                epair = epair,
-                ip = self.ip,
-                mask = self.netmask,
-                iface = self.interface
-            )
-        };
+}
 
-        Ok(IFace {
-            iface: self.interface.clone(),
-            epair: String::from(epair),
-            start_script: script,
-        })
+/// A filesystem mounted into the jail when it starts
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Filesystem {
+    /// host directory for `nullfs`, ignored for `tmpfs`
+    #[serde(default)]
+    pub source: String,
+    /// mount point inside the jail
+    pub target: String,
+    /// `nullfs` or `tmpfs`
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    /// mount options, e.g. `ro` or `size=1g`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl Filesystem {
+    /// Checks the filesystem, errors are reported under `path`
+    pub fn errors(&self, path: &str) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let field = |name: &str| format!("{}.{}", path, name);
+        match self.fs_type.as_str() {
+            "nullfs" => {
+                if !is_abs_path(self.source.as_str()) {
+                    errors.push(ValidationError::new(field("source").as_str(), "Invalid source"))
+                }
+            }
+            "tmpfs" => (),
+            _ => {
+                errors.push(ValidationError::new(
+                    field("type").as_str(),
+                    "Unsupported filesystem type",
+                ))
+            }
+        }
+        if !is_abs_path(self.target.as_str()) || self.target == "/" {
+            errors.push(ValidationError::new(field("target").as_str(), "Invalid target"))
+        }
+        let invalid = |o: &String| {
+            o.is_empty() || o.contains(|c: char| c == ',' || c.is_whitespace())
+        };
+        if self.options.iter().any(invalid) {
+            errors.push(ValidationError::new(field("options").as_str(), "Invalid option"))
+        }
+        errors
     }
 }
 
+/// If `path` is absolute and has no `..` components
+fn is_abs_path(path: &str) -> bool {
+    path.starts_with('/') && !path.split('/').any(|c| c == "..")
+}
+
 /// Jail configuration values
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JailConfig {
@@ -228,6 +501,11 @@ pub struct JailConfig {
     #[serde(default = "empty_nics")]
     pub nics: Vec<NIC>,
 
+    /// static routes, the destination address or CIDR to a gateway
+    /// address or `nics[N]` for a route over that interface
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub routes: BTreeMap<String, String>,
+
     /// maximum number of porocesses (maxproc)
     #[serde(default = "dflt_max_lwp")]
     pub max_lwps: u64,
@@ -242,16 +520,19 @@ pub struct JailConfig {
     /// This jail should not be be part of inventories
     #[serde(skip_serializing_if = "Option::is_none")]
     pub do_not_inventory: Option<bool>,
-    // Currently has no effect
-    /// dns domain for the jail
+    /// dns domain for the jail, used as search domain in resolv.conf
     #[serde(default = "dflt_dns_domain")]
     pub dns_domain: String,
-    // currently no effect
-    /// Prevent the jail delegate to be destroyed
+    /// name servers written to resolv.conf
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resolvers: Vec<String>,
+    /// rewrite resolv.conf on every start, not only on create
+    #[serde(default = "dflt_false")]
+    pub maintain_resolvers: bool,
+    /// Prevent the delegated dataset from being destroyed
     #[serde(skip_serializing_if = "Option::is_none")]
     pub indestructible_delegated: Option<bool>,
-    // currenlty no effect
-    /// Prevent the jail root to be destroyed
+    /// Prevent the jail root from being destroyed
     #[serde(skip_serializing_if = "Option::is_none")]
     pub indestructible_zoneroot: Option<bool>,
     /// UUID of the owner of the jail
@@ -263,10 +544,31 @@ pub struct JailConfig {
     /// Version of the package used for this jail
     #[serde(skip_serializing_if = "Option::is_none")]
     pub package_version: Option<String>,
+    /// metadata readable and writable from inside the jail
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub customer_metadata: BTreeMap<String, String>,
+    /// metadata that is read only inside the jail
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub internal_metadata: BTreeMap<String, String>,
+    /// prefixes of keys, up to the first `:`, that are looked up in
+    /// `internal_metadata`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub internal_metadata_namespaces: Vec<String>,
+    /// create a `data` dataset below the jail root that is managed from
+    /// inside the jail
+    #[serde(default = "dflt_false")]
+    pub delegate_dataset: bool,
+    /// filesystems mounted into the jail when it starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filesystems: Vec<Filesystem>,
+    /// free-form tags to group jails, values are strings, numbers or
+    /// booleans
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, Value>,
+    /// run the `user-script` on every start, not only the first one
+    #[serde(default = "dflt_false")]
+    pub user_script_every_boot: bool,
     // TODO:
-    // customer_metadata: KV
-    // internal_metadata: KV
-    // internal_metadata_namespaces: Vec<String>
     // zfs_data_compression
 }
 
@@ -284,27 +586,57 @@ impl PartialEq for JailConfig {
             self.max_shm_memory == other.max_shm_memory &&
             self.max_locked_memory == other.max_locked_memory &&
             self.nics == other.nics &&
+            self.routes == other.routes &&
             self.max_lwps == other.max_lwps &&
             self.archive_on_delete == other.archive_on_delete &&
             self.billing_id == other.billing_id &&
             self.do_not_inventory == other.do_not_inventory &&
             self.dns_domain == other.dns_domain &&
+            self.resolvers == other.resolvers &&
+            self.maintain_resolvers == other.maintain_resolvers &&
             self.indestructible_delegated == other.indestructible_delegated &&
             self.indestructible_zoneroot == other.indestructible_zoneroot &&
             self.owner_uuid == other.owner_uuid &&
             self.package_name == other.package_name &&
-            self.package_version == other.package_version
+            self.package_version == other.package_version &&
+            self.customer_metadata == other.customer_metadata &&
+            self.internal_metadata == other.internal_metadata &&
+            self.internal_metadata_namespaces == other.internal_metadata_namespaces &&
+            self.delegate_dataset == other.delegate_dataset &&
+            self.filesystems == other.filesystems &&
+            self.tags == other.tags &&
+            self.user_script_every_boot == other.user_script_every_boot
     }
 }
 
 lazy_static! {
   static ref HOSTNAME_RE: Regex = Regex::new("^[a-zA-Z0-9]([a-zA-Z0-9-]{0,253}[a-zA-Z0-9])?$").unwrap();
   static ref ALIAS_RE: Regex = Regex::new("^[a-zA-Z0-9]([a-zA-Z0-9-]{0,253}[a-zA-Z0-9])?$").unwrap();
+  static ref DNS_DOMAIN_RE: Regex = Regex::new("^[a-zA-Z0-9]([a-zA-Z0-9.-]{0,253}[a-zA-Z0-9])?$").unwrap();
   static ref INTERFACE_RE: Regex = Regex::new("^[a-zA-Z]{1,4}[0-9]{0,3}$").unwrap();
-  static ref IP_RE: Regex = Regex::new("^(([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])\\.){3}([0-9]|[1-9][0-9]|1[0-9]{2}|2[0-4][0-9]|25[0-5])$").unwrap();
   static ref MAC_RE: Regex = Regex::new("^[a-fA-F0-9]{1,2}([:][a-fA-F0-9]{1,2}){5}$").unwrap();
 }
 
+/// The MTU of a bridge as reported by ifconfig
+fn bridge_mtu(config: &Config, bridge: &str) -> Option<u32> {
+    match config.executor.run(IFCONFIG, &[bridge]) {
+        Ok(ref output) if output.success() => {
+            output.stdout.lines().next().and_then(|line| {
+                line.split_whitespace()
+                    .skip_while(|f| *f != "mtu")
+                    .nth(1)
+                    .and_then(|mtu| mtu.parse().ok())
+            })
+        }
+        _ => None,
+    }
+}
+
+/// checks if a string is an IPv4 address
+pub fn is_ip(ip: &str) -> bool {
+    Ipv4Addr::from_str(ip).is_ok()
+}
+
 impl JailConfig {
     /// Reads a new config from a file
     pub fn from_file(config: &Config, config_path: &str) -> Result<Self, Box<Error>> {
@@ -314,10 +646,37 @@ impl JailConfig {
 
     /// Reads the config from a reader
     pub fn from_reader<R>(config: &Config, reader: R) -> Result<Self, Box<Error>>
+    where
+        R: Read,
+    {
+        let conf = JailConfig::parse(reader)?;
+        match conf.errors(config) {
+            Some(errors) => Err(ValidationErrors::bx(errors)),
+            None => Ok(conf),
+        }
+    }
+
+    /// Reads a config given on create, it is checked more strictly than
+    /// a stored one
+    pub fn from_input<R>(config: &Config, reader: R) -> Result<Self, Box<Error>>
+    where
+        R: Read,
+    {
+        let conf = JailConfig::parse(reader)?;
+        match conf.input_errors(config) {
+            Some(errors) => Err(ValidationErrors::bx(errors)),
+            None => Ok(conf),
+        }
+    }
+
+    fn parse<R>(reader: R) -> Result<Self, Box<Error>>
     where
         R: Read,
     {
         let mut conf: JailConfig = serde_json::from_reader(reader)?;
+        for nic in conf.nics.iter_mut() {
+            nic.normalize();
+        }
         let max_physical_memory = conf.max_physical_memory;
         if conf.max_shm_memory.is_none() {
             conf.max_shm_memory = Some(max_physical_memory);
@@ -325,13 +684,40 @@ impl JailConfig {
         if conf.max_locked_memory.is_none() {
             conf.max_locked_memory = Some(max_physical_memory);
         }
-        match conf.errors(config) {
-            Some(errors) => Err(ValidationErrors::bx(errors)),
-            None => Ok(conf),
-        }
+        Ok(conf)
     }
+
     /// checks the config for errors
     pub fn errors(&self, config: &Config) -> Option<Vec<ValidationError>> {
+        self.checked(config, false)
+    }
+
+    /// checks a config given on create for errors, see
+    /// `NIC::input_errors`
+    pub fn input_errors(&self, config: &Config) -> Option<Vec<ValidationError>> {
+        self.checked(config, true)
+    }
+
+    fn checked(&self, config: &Config, input: bool) -> Option<Vec<ValidationError>> {
+        let mut errors = self.field_errors();
+        for (i, nic) in self.nics.iter().enumerate() {
+            let path = format!("nics[{}]", i);
+            if input {
+                errors.append(&mut nic.input_errors(config, path.as_str()));
+            } else {
+                errors.append(&mut nic.errors(config, path.as_str()));
+            }
+        }
+        if errors.is_empty() {
+            None
+        } else {
+            Some(errors)
+        }
+
+    }
+
+    /// checks everything but the individual nics for errors
+    pub fn field_errors(&self) -> Vec<ValidationError> {
         let mut errors = Vec::new();
         if !HOSTNAME_RE.is_match(self.hostname.as_str()) {
             errors.push(ValidationError::new("hostname", "Invalid hostname"))
@@ -339,59 +725,124 @@ impl JailConfig {
         if !ALIAS_RE.is_match(self.alias.as_str()) {
             errors.push(ValidationError::new("alias", "Invalid alias"))
         }
-        let mut i = 0;
-        for nic in self.nics.clone() {
-            if !INTERFACE_RE.is_match(nic.interface.as_str()) {
-                errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid interface name",
-                ))
-            }
-            if !IP_RE.is_match(nic.ip.as_str()) {
+        if self.nics.iter().filter(|nic| nic.primary).count() > 1 {
+            errors.push(ValidationError::new("nics", "More than one primary nic"))
+        }
+        if !DNS_DOMAIN_RE.is_match(self.dns_domain.as_str()) {
+            errors.push(ValidationError::new("dns_domain", "Invalid dns domain"))
+        }
+        for (i, resolver) in self.resolvers.iter().enumerate() {
+            if IpAddr::from_str(resolver).is_err() {
                 errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid ip",
+                    format!("resolvers[{}]", i).as_str(),
+                    "Invalid resolver",
                 ))
             }
-            if !IP_RE.is_match(nic.netmask.as_str()) {
-                errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid netmask",
-                ))
+        }
+        for (dest, gateway) in self.routes.iter() {
+            let path = format!("routes.{}", dest);
+            let dest = match dest.parse::<Cidr>() {
+                Ok(cidr) => Some(cidr.addr),
+                Err(_) => IpAddr::from_str(dest).ok(),
+            };
+            let dest = match dest {
+                Some(dest) => dest,
+                None => {
+                    errors.push(ValidationError::new(path.as_str(), "Invalid destination"));
+                    continue;
+                }
+            };
+            if self.route_nic(gateway).is_some() {
+                continue;
             }
-            if !IP_RE.is_match(nic.gateway.as_str()) {
-                errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid gateway",
-                ))
+            match IpAddr::from_str(gateway) {
+                Ok(gateway) if gateway.is_ipv4() == dest.is_ipv4() => (),
+                Ok(_) => errors.push(ValidationError::new(path.as_str(), "Gateway of the wrong family")),
+                Err(_) if gateway.starts_with("nics[") => {
+                    errors.push(ValidationError::new(path.as_str(), "Unknown nic"))
+                }
+                Err(_) => errors.push(ValidationError::new(path.as_str(), "Invalid gateway")),
             }
-            if !MAC_RE.is_match(nic.mac.as_str()) {
-                errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Invalid mac",
-                ))
+        }
+        for (i, fs) in self.filesystems.iter().enumerate() {
+            errors.append(&mut fs.errors(format!("filesystems[{}]", i).as_str()));
+        }
+        for (key, value) in self.tags.iter() {
+            match *value {
+                Value::String(_) | Value::Number(_) | Value::Bool(_) => (),
+                _ => {
+                    errors.push(ValidationError::new(
+                        format!("tags.{}", key).as_str(),
+                        "Tags must be strings, numbers or booleans",
+                    ))
+                }
             }
-            if !config.settings.networks.contains_key(&nic.nic_tag) {
-                errors.push(ValidationError::new(
-                    format!("nic[{}]", i).as_str(),
-                    "Unknown nic_tag",
-                ))
+        }
+        errors
+    }
 
-            }
-            i = i + 1;
+    /// The nic a route gateway like `nics[1]` refers to
+    fn route_nic(&self, gateway: &str) -> Option<&NIC> {
+        if !gateway.starts_with("nics[") || !gateway.ends_with(']') {
+            return None;
         }
-        if errors.is_empty() {
-            None
-        } else {
-            Some(errors)
+        gateway[5..gateway.len() - 1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| self.nics.get(i))
+    }
+
+    /// The `route` command to add or delete the route to `dest`
+    pub fn route_command(&self, action: &str, dest: &str) -> String {
+        let gateway = match self.routes.get(dest) {
+            Some(gateway) => gateway,
+            None => return String::new(),
+        };
+        let family = if dest.contains(':') { " -inet6" } else { "" };
+        let kind = if dest.contains('/') { "-net" } else { "-host" };
+        let target = match self.route_nic(gateway) {
+            Some(nic) => format!("-interface {}", nic.interface),
+            None => gateway.clone(),
+        };
+        format!("/sbin/route -q {}{} {} {} {}; ", action, family, kind, dest, target)
+    }
+
+    /// Contents of the jails resolv.conf
+    pub fn resolv_conf(&self) -> String {
+        let mut conf = format!("search {}\n", self.dns_domain);
+        for resolver in self.resolvers.iter() {
+            conf.push_str(format!("nameserver {}\n", resolver).as_str());
         }
+        conf
+    }
+
+    /// Command that writes resolv.conf from the outer jail into the inner
+    /// one, symlinks in the inner jail can not point outside of the outer
+    /// jail this way
+    pub fn resolv_conf_script(&self) -> String {
+        format!(
+            "printf '{}' > /jail/etc/resolv.conf; ",
+            self.resolv_conf().replace('\n', "\\n")
+        )
+    }
+
+    /// The `user-script` from the customer metadata, if there is one
+    pub fn user_script(&self) -> Option<&String> {
+        self.customer_metadata.get(USER_SCRIPT)
+    }
 
+    /// Commands to add all static routes
+    pub fn routes_script(&self) -> String {
+        self.routes
+            .keys()
+            .map(|dest| self.route_command("add", dest))
+            .collect()
     }
 
     /// Translates the config into resource controle limts
     pub fn rctl_limits(&self) -> Vec<String> {
         let mut res = Vec::new();
-        let uuid = self.uuid.clone();
+        let uuid = self.uuid;
         let mut base = String::from("jail:");
         base.push_str(uuid.hyphenated().to_string().as_str());
 
@@ -401,7 +852,7 @@ impl JailConfig {
         let mut mem = base.clone();
         mem.push_str(":memoryuse:deny=");
         mem.push_str(max_physical_memory.as_str());
-        mem.push_str("M");
+        mem.push('M');
         res.push(mem);
 
         let mut memorylocked = base.clone();
@@ -412,7 +863,7 @@ impl JailConfig {
             }
             None => memorylocked.push_str(max_physical_memory.as_str()),
         }
-        memorylocked.push_str("M");
+        memorylocked.push('M');
         res.push(memorylocked);
 
         let mut shmsize = base.clone();
@@ -421,7 +872,7 @@ impl JailConfig {
             Some(max_shm_memory) => shmsize.push_str(max_shm_memory.to_string().as_str()),
             None => shmsize.push_str(max_physical_memory.as_str()),
         }
-        shmsize.push_str("M");
+        shmsize.push('M');
         res.push(shmsize);
 
         let mut pcpu = base.clone();
@@ -477,3 +928,209 @@ fn new_mac() -> String {
         rng.gen::<u8>()
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use jail_config::*;
+    use config::Simulated;
+    use serde_json;
+
+    fn nic(json: &str) -> NIC {
+        let mut nic: NIC = serde_json::from_str(json).unwrap();
+        nic.normalize();
+        nic
+    }
+
+    #[test]
+    fn normalize() {
+        let legacy = nic(
+            r#"{"interface": "net0", "nic_tag": "admin", "ip": "10.0.0.5",
+                "netmask": "255.255.255.0", "gateway": "10.0.0.1"}"#,
+        );
+        assert_eq!(vec![String::from("10.0.0.5/24")], legacy.ips);
+        assert_eq!(vec![String::from("10.0.0.1")], legacy.gateways);
+        let cidr = nic(
+            r#"{"interface": "net0", "nic_tag": "admin",
+                "ips": ["fd00::5/64", "10.0.0.5/23"], "gateways": ["fd00::1", "10.0.0.1"]}"#,
+        );
+        assert_eq!("10.0.0.5", cidr.ip);
+        assert_eq!("255.255.254.0", cidr.netmask);
+        assert_eq!("10.0.0.1", cidr.gateway);
+    }
+
+    #[test]
+    fn errors() {
+        let (config, _) = Simulated::new();
+        let nic = nic(
+            r#"{"interface": "net0", "nic_tag": "admin",
+                "ips": ["10.0.0.5/24", "fd00::5/64", "fd00::6"],
+                "gateways": ["10.0.1.1", "fd00::1", "nope"]}"#,
+        );
+        let errors: Vec<String> = nic.input_errors(&config, "nics[0]")
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "nics[0].ips[2]: Invalid address",
+                "nics[0].gateways[2]: Invalid gateway",
+                "nics[0].gateways[0]: Gateway not reachable",
+            ],
+            errors
+        );
+        // stored configs are not held to it
+        assert_eq!(2, nic.errors(&config, "nics[0]").len());
+    }
+
+    #[test]
+    fn mtu() {
+        use config::Network;
+        let (mut config, sim) = Simulated::new();
+        sim.add_bridge("bridge0", 1500);
+        sim.add_bridge("bridge1", 9000);
+        config.settings.networks.insert(
+            String::from("jumbo"),
+            Network::Detailed {
+                bridge: String::from("bridge1"),
+                mtu: Some(9000),
+            },
+        );
+        let admin = nic(
+            r#"{"interface": "net0", "nic_tag": "admin", "mtu": 9000, "ips": ["10.0.0.5/24"]}"#,
+        );
+        let jumbo = nic(
+            r#"{"interface": "net1", "nic_tag": "jumbo", "vlan": 5, "ips": ["10.1.0.5/24"]}"#,
+        );
+        let errors: Vec<String> = admin
+            .input_errors(&config, "nics[0]")
+            .iter()
+            .chain(jumbo.input_errors(&config, "nics[1]").iter())
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(vec!["nics[0].mtu: MTU larger than the MTU of bridge0 (1500)"], errors);
+        // loading a stored config does not ask the bridge
+        assert!(admin.errors(&config, "nics[0]").is_empty());
+        assert_eq!(Some(9000), jumbo.effective_mtu(&config));
+        let iface = jumbo.get_iface(&config, &Uuid::nil()).unwrap();
+        assert!(iface.start_script.contains("/sbin/ifconfig net1 mtu 9000; "));
+    }
+
+    #[test]
+    fn address_script() {
+        let nic = nic(
+            r#"{"interface": "net0", "nic_tag": "admin", "primary": true,
+                "ips": ["10.0.0.5/24", "fd00::5/64", "10.0.0.6/24"],
+                "gateways": ["10.0.0.1", "fd00::1"]}"#,
+        );
+        assert_eq!(
+            "/sbin/ifconfig net0 inet6 -ifdisabled; \
+             /sbin/ifconfig net0 inet 10.0.0.5/24; \
+             /sbin/ifconfig net0 inet6 fd00::5/64; \
+             /sbin/ifconfig net0 inet 10.0.0.6/24 alias; \
+             /sbin/route add default -gateway 10.0.0.1; \
+             /sbin/route add -inet6 default fd00::1; ",
+            nic.address_script()
+        );
+        assert_eq!("", nic.autoconf_script());
+    }
+
+    #[test]
+    fn routes() {
+        let conf = r#"{"image_uuid": "00000000-0000-0000-0000-000000000000",
+                       "alias": "test", "hostname": "test", "max_physical_memory": 1024,
+                       "cpu_cap": 100, "quota": 5,
+                       "nics": [{"interface": "net0", "nic_tag": "admin",
+                                 "ips": ["10.0.0.5/24", "fd00::5/64"]},
+                                {"interface": "net1", "nic_tag": "admin",
+                                 "ips": ["10.1.0.5/24"]}],
+                       "routes": {"10.2.0.0/16": "10.0.0.1", "10.3.0.1": "nics[1]",
+                                  "fd01::/48": "fd00::1", "10.4.0.0/16": "fd00::1",
+                                  "10.5.0.0/16": "nics[2]", "nope": "10.0.0.1"}}"#;
+        let conf: JailConfig = serde_json::from_str(conf).unwrap();
+        let errors: Vec<String> = conf.field_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "routes.10.4.0.0/16: Gateway of the wrong family",
+                "routes.10.5.0.0/16: Unknown nic",
+                "routes.nope: Invalid destination",
+            ],
+            errors
+        );
+        assert_eq!(
+            "/sbin/route -q add -net 10.2.0.0/16 10.0.0.1; ",
+            conf.route_command("add", "10.2.0.0/16")
+        );
+        assert_eq!(
+            "/sbin/route -q delete -host 10.3.0.1 -interface net1; ",
+            conf.route_command("delete", "10.3.0.1")
+        );
+        assert_eq!(
+            "/sbin/route -q add -inet6 -net fd01::/48 fd00::1; ",
+            conf.route_command("add", "fd01::/48")
+        );
+    }
+
+    #[test]
+    fn resolvers() {
+        let conf = r#"{"image_uuid": "00000000-0000-0000-0000-000000000000",
+                       "alias": "test", "hostname": "test", "max_physical_memory": 1024,
+                       "cpu_cap": 100, "quota": 5, "dns_domain": "example.com",
+                       "resolvers": ["10.0.0.53", "fd00::53", "'; reboot"]}"#;
+        let mut conf: JailConfig = serde_json::from_str(conf).unwrap();
+        let errors: Vec<String> = conf.field_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["resolvers[2]: Invalid resolver"], errors);
+        conf.resolvers.pop();
+        assert_eq!(
+            "search example.com\nnameserver 10.0.0.53\nnameserver fd00::53\n",
+            conf.resolv_conf()
+        );
+        assert_eq!(
+            r"printf 'search example.com\nnameserver 10.0.0.53\nnameserver fd00::53\n' > /jail/etc/resolv.conf; ",
+            conf.resolv_conf_script()
+        );
+    }
+
+    #[test]
+    fn filesystems() {
+        let conf = r#"{"image_uuid": "00000000-0000-0000-0000-000000000000",
+                       "alias": "test", "hostname": "test", "max_physical_memory": 1024,
+                       "cpu_cap": 100, "quota": 5,
+                       "filesystems": [
+                           {"type": "nullfs", "source": "/data/www", "target": "/www",
+                            "options": ["ro"]},
+                           {"type": "tmpfs", "target": "/tmp", "options": ["size=1g"]},
+                           {"type": "nullfs", "source": "data", "target": "/../etc"},
+                           {"type": "procfs", "target": "/proc", "options": ["ro,rw"]}
+                       ]}"#;
+        let conf: JailConfig = serde_json::from_str(conf).unwrap();
+        let errors: Vec<String> = conf.field_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "filesystems[2].source: Invalid source",
+                "filesystems[2].target: Invalid target",
+                "filesystems[3].type: Unsupported filesystem type",
+                "filesystems[3].options: Invalid option",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn autoconf() {
+        let legacy = nic(r#"{"interface": "net0", "nic_tag": "admin", "ip": "dhcp"}"#);
+        assert_eq!(vec![String::from("dhcp")], legacy.ips);
+        let nic = nic(
+            r#"{"interface": "net0", "nic_tag": "admin", "primary": true,
+                "ips": ["dhcp", "addrconf", "fd00::5/64"], "gateways": ["10.0.0.1"]}"#,
+        );
+        assert_eq!("dhcp", nic.ip);
+        assert_eq!("", nic.netmask);
+        assert_eq!(
+            "/sbin/ifconfig net0 inet6 -ifdisabled accept_rtadv; \
+             /sbin/ifconfig net0 inet6 fd00::5/64; \
+             /sbin/route add default -gateway 10.0.0.1; ",
+            nic.address_script()
+        );
+        assert_eq!("/sbin/dhclient -b net0; /sbin/rtsol net0; ", nic.autoconf_script());
+    }
+}
@@ -0,0 +1,158 @@
+//! Extra filesystems and the delegated dataset of a jail
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use config::Config;
+use errors::GenericError;
+use jail_config::Filesystem;
+use zfs;
+
+use super::{Jail, JEXEC, MOUNT, UMOUNT};
+
+static MKDIR: &'static str = "mkdir";
+
+/// Dataset below a jail root that is handed to the jail with
+/// `delegate_dataset`
+pub fn delegated_dataset(root: &str) -> String {
+    format!("{}/data", root)
+}
+
+impl<'a> Jail<'a> {
+    /// Host path of a filesystem target inside the inner jail
+    fn mount_point(&self, fs: &Filesystem) -> String {
+        format!("/{}/root/jail{}", self.idx.root, fs.target)
+    }
+
+    /// Mounts the configured filesystems, missing targets are created.
+    /// This happens before the jail runs so it can not swap the targets
+    /// for symlinks in between.
+    pub fn mount_filesystems(&self, config: &Config) -> Result<i32, Box<Error>> {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let jail_root = format!("/{}/root/jail", self.idx.root);
+        for fs in self.config.filesystems.iter() {
+            let target = self.mount_point(fs);
+            check_confined(jail_root.as_str(), target.as_str())?;
+            let output = config.executor.run(MKDIR, &["-p", target.as_str()])?;
+            if !output.success() {
+                crit!("failed to create mount point"; "vm" => uuid.clone(), "target" => target);
+                return Err(GenericError::bx("Could not create mount point"));
+            }
+            check_confined(jail_root.as_str(), target.as_str())?;
+            let source = if fs.fs_type == "tmpfs" {
+                "tmpfs"
+            } else {
+                fs.source.as_str()
+            };
+            let options = fs.options.join(",");
+            let mut args = vec!["-t", fs.fs_type.as_str()];
+            if !options.is_empty() {
+                args.push("-o");
+                args.push(options.as_str());
+            }
+            args.push(source);
+            args.push(target.as_str());
+            debug!("mounting filesystem in inner jail"; "vm" => uuid.clone(), "args" => args.join(" "));
+            let output = config.executor.run(MOUNT, &args)?;
+            if !output.success() {
+                crit!("failed to mount filesystem"; "vm" => uuid.clone(), "target" => target);
+                return Err(GenericError::bx("Could not mount filesystem"));
+            }
+        }
+        Ok(0)
+    }
+
+    /// Unmounts the configured filesystems in reverse order, targets
+    /// the jail swapped for symlinks that lead outside of it are left
+    /// alone. All filesystems are tried, the first failure is returned.
+    pub fn umount_filesystems(&self, config: &Config) -> Result<i32, Box<Error>> {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let jail_root = format!("/{}/root/jail", self.idx.root);
+        let mut res = Ok(0);
+        for fs in self.config.filesystems.iter().rev() {
+            let target = self.mount_point(fs);
+            if let Err(e) = check_confined(jail_root.as_str(), target.as_str()) {
+                crit!("refusing to unmount filesystem"; "vm" => uuid.clone(),
+                      "target" => target, "error" => e.to_string());
+                if res.is_ok() {
+                    res = Err(e);
+                }
+                continue;
+            }
+            debug!("un mounting filesystem in inner jail"; "vm" => uuid.clone(),
+                   "target" => target.clone());
+            let output = config.executor.run(UMOUNT, &[target.as_str()])?;
+            if !output.success() {
+                crit!("failed to unmount filesystem"; "vm" => uuid.clone(),
+                      "target" => target, "stderr" => output.stderr);
+                if res.is_ok() {
+                    res = Err(GenericError::bx("Could not unmount filesystem"));
+                }
+            }
+        }
+        res
+    }
+
+    /// Attaches the delegated dataset to the freshly started inner jail
+    /// and mounts it there
+    pub fn attach_delegated(&self, config: &Config) -> Result<i32, Box<Error>> {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let inner = format!("{0}.{0}", uuid);
+        let dataset = delegated_dataset(self.idx.root.as_str());
+        zfs::jail(config, inner.as_str(), dataset.as_str())?;
+        let output = config.executor.run(
+            JEXEC,
+            &[inner.as_str(), "/sbin/zfs", "mount", "-a"],
+        )?;
+        if !output.success() {
+            warn!("failed to mount delegated dataset"; "vm" => uuid, "stderr" => output.stderr);
+        }
+        Ok(0)
+    }
+}
+
+/// Refuses mount points that resolve outside of the jail root through
+/// symlinks, only the part of the path that exists is checked.
+fn check_confined(jail_root: &str, target: &str) -> Result<(), Box<Error>> {
+    let root = match fs::canonicalize(jail_root) {
+        Ok(root) => root,
+        Err(_) => return Ok(()),
+    };
+    let mut existing = PathBuf::from(target);
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return Ok(()),
+        };
+    }
+    let resolved = fs::canonicalize(&existing)?;
+    if resolved.starts_with(&root) {
+        Ok(())
+    } else {
+        Err(GenericError::bx(
+            format!("Mount target {} is outside of the jail", target).as_str(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use config::TempDir;
+    use jails::filesystems::check_confined;
+
+    #[test]
+    fn confined() {
+        let dir = TempDir::new();
+        let root = dir.path().join("jail");
+        fs::create_dir_all(root.join("data")).unwrap();
+        symlink("/etc", root.join("escape")).unwrap();
+        let root_str = root.to_str().unwrap();
+        assert!(check_confined(root_str, root.join("data/db").to_str().unwrap()).is_ok());
+        assert!(check_confined(root_str, root.join("new/dir").to_str().unwrap()).is_ok());
+        assert!(check_confined(root_str, root.join("escape").to_str().unwrap()).is_err());
+        assert!(check_confined(root_str, root.join("escape/ssh").to_str().unwrap()).is_err());
+    }
+}
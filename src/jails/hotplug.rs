@@ -0,0 +1,160 @@
+//! Adding, removing and reconfiguring NICs, routes and resolvers of
+//! running jails
+
+use std::error::Error;
+
+use config::Config;
+use errors::GenericError;
+use jail_config::{JailConfig, NIC};
+
+use super::{Jail, IFCONFIG, JEXEC};
+
+impl<'a> Jail<'a> {
+    /// jid of the outer jail that owns the network stack
+    fn outer_jid(&self) -> Result<String, Box<Error>> {
+        match self.outer {
+            Some(outer) => Ok(outer.id.to_string()),
+            None => Err(GenericError::bx("VM is not running")),
+        }
+    }
+
+    /// Name of the host side end of a NIC's epair
+    fn host_iface(&self, nic: &NIC) -> Result<String, Box<Error>> {
+        Ok(format!("j{}:{}", self.outer_jid()?, nic.interface))
+    }
+
+    /// Runs a shell script in the outer jail
+    pub fn run_in_outer(&self, config: &Config, script: &str) -> Result<i32, Box<Error>> {
+        let jid = self.outer_jid()?;
+        debug!("running in outer jail"; "vm" => self.idx.uuid.hyphenated().to_string(),
+               "script" => script);
+        let output = config.executor.run(JEXEC, &[jid.as_str(), "/bin/sh", "-c", script])?;
+        if !output.success() {
+            crit!("script failed in outer jail"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                  "stderr" => output.stderr);
+            return Err(GenericError::bx("Failed to configure interface in jail"));
+        }
+        Ok(0)
+    }
+
+    /// Creates the epair of a NIC, moves the b side into the jail and
+    /// configures it, the epair is destroyed again if a step fails.
+    pub fn plug_nic(&self, config: &Config, nic: &NIC) -> Result<i32, Box<Error>> {
+        let jid = self.outer_jid()?;
+        let iface = nic.get_iface(config, &self.idx.uuid)?;
+        let mut epaira = iface.epair.clone();
+        epaira.push('a');
+        let mut epairb = iface.epair.clone();
+        epairb.push('b');
+        debug!("plugging nic"; "vm" => self.idx.uuid.hyphenated().to_string(),
+               "interface" => nic.interface.clone(), "epair" => iface.epair.clone());
+        let res = config
+            .executor
+            .run(IFCONFIG, &[epairb.as_str(), "vnet", jid.as_str()])
+            .and_then(|output| if output.success() {
+                Ok(0)
+            } else {
+                Err(GenericError::bx("could not move interface into jail"))
+            })
+            .and_then(|_| {
+                let mut script = iface.start_script.clone();
+                script.push_str(nic.autoconf_script().as_str());
+                self.run_in_outer(config, script.as_str())
+            });
+        if let Err(e) = res {
+            let _ = config.executor.run(IFCONFIG, &[epaira.as_str(), "destroy"]);
+            return Err(e);
+        }
+        let host = self.host_iface(nic)?;
+        let output = config.executor.run(IFCONFIG, &[epaira.as_str(), "name", host.as_str()])?;
+        if !output.success() {
+            crit!("failed to rename interface"; "vm" => self.idx.uuid.hyphenated().to_string());
+        }
+        Ok(0)
+    }
+
+    /// Destroys the epair of a NIC
+    pub fn unplug_nic(&self, config: &Config, nic: &NIC) -> Result<i32, Box<Error>> {
+        let host = self.host_iface(nic)?;
+        debug!("unplugging nic"; "vm" => self.idx.uuid.hyphenated().to_string(),
+               "interface" => host.clone());
+        let output = config.executor.run(IFCONFIG, &[host.as_str(), "destroy"])?;
+        if !output.success() {
+            return Err(GenericError::bx("could not destroy interface"));
+        }
+        Ok(0)
+    }
+
+    /// Replaces the addresses, MTU and, for the primary NIC, the
+    /// default routes of `from` with the ones of `to` inside the jail
+    pub fn configure_nic(&self, config: &Config, from: &NIC, to: &NIC) -> Result<i32, Box<Error>> {
+        let mut script = String::new();
+        if from.dhcp() {
+            script.push_str(format!("/bin/pkill -f 'dhclient: {}'; ", from.interface).as_str());
+        }
+        if from.addrconf() && !to.addrconf() {
+            script.push_str(format!("/sbin/ifconfig {} inet6 -accept_rtadv; ", from.interface).as_str());
+        }
+        for cidr in from.cidrs() {
+            let family = if cidr.is_ipv4() { "inet" } else { "inet6" };
+            script.push_str(
+                format!("/sbin/ifconfig {} {} {} -alias; ", from.interface, family, cidr.addr)
+                    .as_str(),
+            );
+        }
+        if from.primary {
+            script.push_str("/sbin/route -q delete default; /sbin/route -q delete -inet6 default; ");
+        }
+        let mtu = to.effective_mtu(config);
+        if let Some(mtu) = mtu {
+            if mtu != from.effective_mtu(config).unwrap_or(0) {
+                let host = self.host_iface(to)?;
+                let output = config.executor.run(IFCONFIG, &[host.as_str(), "mtu", mtu.to_string().as_str()])?;
+                if !output.success() {
+                    return Err(GenericError::bx("could not set mtu"));
+                }
+                if to.vlan.is_some() {
+                    script.push_str(format!("/sbin/ifconfig {}p mtu {}; ", to.interface, mtu).as_str());
+                }
+            }
+            script.push_str(format!("/sbin/ifconfig {} mtu {}; ", to.interface, mtu).as_str());
+        }
+        script.push_str(to.address_script().as_str());
+        script.push_str(to.autoconf_script().as_str());
+        self.run_in_outer(config, script.as_str())
+    }
+
+    /// Rewrites resolv.conf of the inner jail
+    pub fn write_resolvers(&self, config: &Config, jail: &JailConfig) -> Result<i32, Box<Error>> {
+        debug!("writing resolv.conf"; "vm" => self.idx.uuid.hyphenated().to_string());
+        self.run_in_outer(config, jail.resolv_conf_script().as_str())
+    }
+
+    /// Runs the route commands built by `route_changes`
+    pub fn change_routes(&self, config: &Config, script: &str) -> Result<i32, Box<Error>> {
+        debug!("changing routes"; "vm" => self.idx.uuid.hyphenated().to_string());
+        self.run_in_outer(config, script)
+    }
+}
+
+/// Commands to delete the routes of `from` that are gone or changed in `to`
+/// and add the new ones
+pub fn route_changes(from: &JailConfig, to: &JailConfig) -> String {
+    let mut script = String::new();
+    for (dest, gateway) in from.routes.iter() {
+        if to.routes.get(dest) != Some(gateway) {
+            script.push_str(from.route_command("delete", dest).as_str());
+        }
+    }
+    for (dest, gateway) in to.routes.iter() {
+        if from.routes.get(dest) != Some(gateway) {
+            script.push_str(to.route_command("add", dest).as_str());
+        }
+    }
+    script
+}
+
+/// If a NIC change can be applied without a restart
+pub fn live_change(from: &NIC, to: &NIC) -> bool {
+    from.interface == to.interface && from.nic_tag == to.nic_tag && from.vlan == to.vlan
+}
@@ -0,0 +1,368 @@
+//! Runtime details of a running jail
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use uuid::Uuid;
+
+use config::Config;
+use jdb::{JailState, ScriptResult};
+use zfs;
+
+use super::{Jail, JEXEC, JLS, MOUNT, RCTL, IFCONFIG};
+
+/// A resource limit along with the current usage
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RctlInfo {
+    /// rctl resource, e.g. `memoryuse`
+    pub resource: String,
+    /// action taken when the limit is hit
+    pub action: String,
+    /// configured limit
+    pub limit: String,
+    /// current usage, `None` when the jail is not running
+    pub usage: Option<u64>,
+}
+
+/// Interfaces backing a NIC
+#[derive(Debug, Serialize)]
+pub struct NicInfo {
+    /// interface name in the config
+    pub interface: String,
+    /// host side end of the epair
+    pub host_interface: String,
+    /// jail side end of the epair
+    pub jail_interface: String,
+    /// if the host side interface exists
+    pub present: bool,
+    /// addresses the interface has inside the jail, including the ones
+    /// assigned by DHCP or router advertisements
+    pub addresses: Vec<String>,
+}
+
+/// A filesystem mounted under the jail root
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MountInfo {
+    /// mounted device or filesystem
+    pub source: String,
+    /// mount point
+    pub target: String,
+    /// filesystem type
+    pub fstype: String,
+}
+
+/// Space used by the jails dataset
+#[derive(Debug, Serialize)]
+pub struct ZfsInfo {
+    /// dataset of the jail root
+    pub dataset: String,
+    /// bytes used
+    pub used: u64,
+    /// bytes available
+    pub avail: u64,
+    /// quota in bytes, 0 if there is none
+    pub quota: u64,
+}
+
+/// Runtime details of a jail as reported by `vmadm info`
+#[derive(Debug, Serialize)]
+pub struct Info {
+    /// uuid of the jail
+    pub uuid: Uuid,
+    /// lifecycle state from the index
+    pub state: JailState,
+    /// jid of the outer jail
+    pub outer_jid: Option<u64>,
+    /// jid of the inner jail
+    pub inner_jid: Option<u64>,
+    /// jail parameters read from the kernel for the outer and inner jail
+    pub parameters: BTreeMap<String, BTreeMap<String, String>>,
+    /// resource limits and usage
+    pub rctl: Vec<RctlInfo>,
+    /// interfaces of the jails NICs
+    pub nics: Vec<NicInfo>,
+    /// filesystems mounted under the jail root
+    pub mounts: Vec<MountInfo>,
+    /// space used by the jail root
+    pub zfs: Option<ZfsInfo>,
+    /// result of the last `user-script` run, `None` if it did not run yet
+    pub user_script: Option<ScriptResult>,
+}
+
+impl<'a> Jail<'a> {
+    /// Collects runtime details, parts that can not be read are left
+    /// empty and logged.
+    pub fn info(&self, config: &Config) -> Result<Info, Box<Error>> {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let mut parameters = BTreeMap::new();
+        if let Some(outer) = self.outer {
+            parameters.insert(String::from("outer"), jail_parameters(config, outer.id)?);
+        }
+        if let Some(inner) = self.inner {
+            parameters.insert(String::from("inner"), jail_parameters(config, inner.id)?);
+        }
+        let usage = if self.outer.is_some() {
+            rctl_usage(config, uuid.as_str())?
+        } else {
+            BTreeMap::new()
+        };
+        let rctl = self.config
+            .rctl_limits()
+            .iter()
+            .filter_map(|rule| parse_rule(rule))
+            .map(|mut limit| {
+                limit.usage = usage.get(&limit.resource).cloned();
+                limit
+            })
+            .collect();
+        let mut nics = Vec::new();
+        if let Some(outer) = self.outer {
+            for nic in self.config.nics.iter() {
+                let host_interface = format!("j{}:{}", outer.id, nic.interface);
+                let present = config
+                    .executor
+                    .run(IFCONFIG, &[host_interface.as_str()])?
+                    .success();
+                let outer_jid = outer.id.to_string();
+                let output = config.executor.run(
+                    JEXEC,
+                    &[outer_jid.as_str(), IFCONFIG, nic.interface.as_str()],
+                )?;
+                let addresses = if output.success() {
+                    parse_addresses(output.stdout.as_str())
+                } else {
+                    warn!("failed to read addresses"; "vm" => uuid.clone(),
+                          "interface" => nic.interface.clone(), "stderr" => output.stderr);
+                    Vec::new()
+                };
+                nics.push(NicInfo {
+                    interface: nic.interface.clone(),
+                    host_interface: host_interface,
+                    jail_interface: nic.interface.clone(),
+                    present: present,
+                    addresses: addresses,
+                });
+            }
+        }
+        let mut root = String::from("/");
+        root.push_str(self.idx.root.as_str());
+        let mounts = mounts(config, root.as_str())?;
+        let zfs = match zfs::get(config, self.idx.root.as_str()) {
+            Ok(entry) => {
+                let quota = zfs::get_property(config, self.idx.root.as_str(), "quota")
+                    .ok()
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(0);
+                Some(ZfsInfo {
+                    dataset: self.idx.root.clone(),
+                    used: entry.used,
+                    avail: entry.avail,
+                    quota: quota,
+                })
+            }
+            Err(e) => {
+                warn!("failed to read dataset"; "vm" => uuid.clone(), "error" => e.to_string());
+                None
+            }
+        };
+        Ok(Info {
+            uuid: self.idx.uuid,
+            state: self.idx.state,
+            outer_jid: self.outer.map(|o| o.id),
+            inner_jid: self.inner.map(|i| i.id),
+            parameters: parameters,
+            rctl: rctl,
+            nics: nics,
+            mounts: mounts,
+            zfs: zfs,
+            user_script: self.idx.user_script.clone(),
+        })
+    }
+}
+
+/// Reads all parameters of a jail with `jls -n -q`
+fn jail_parameters(config: &Config, jid: u64) -> Result<BTreeMap<String, String>, Box<Error>> {
+    let jid = jid.to_string();
+    let output = config.executor.run(JLS, &["-j", jid.as_str(), "-n", "-q"])?;
+    if !output.success() {
+        warn!("failed to read jail parameters"; "jid" => jid, "stderr" => output.stderr);
+        return Ok(BTreeMap::new());
+    }
+    Ok(parse_parameters(output.stdout.trim()))
+}
+
+/// Parses `key=value` pairs as printed by `jls -n -q`, values with
+/// spaces are quoted.
+fn parse_parameters(line: &str) -> BTreeMap<String, String> {
+    let mut res = BTreeMap::new();
+    let mut token = String::new();
+    let mut tokens = Vec::new();
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                if let Some(escaped) = chars.next() {
+                    token.push(escaped)
+                }
+            }
+            ' ' | '\t' if !quoted => {
+                if !token.is_empty() {
+                    tokens.push(token.clone());
+                    token.clear();
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    for token in tokens {
+        let mut kv = token.splitn(2, '=');
+        let key = kv.next().unwrap_or("");
+        if key.is_empty() {
+            continue;
+        }
+        // boolean parameters are printed as `param` or `noparam`
+        let value = kv.next().unwrap_or("true");
+        res.insert(String::from(key), String::from(value));
+    }
+    res
+}
+
+/// Reads the `inet` and `inet6` lines printed by `ifconfig <iface>` as
+/// CIDRs, IPv6 scopes are dropped.
+fn parse_addresses(output: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let prefix = match (fields[0], fields[2]) {
+            ("inet", "netmask") => {
+                u32::from_str_radix(fields[3].replace("0x", "").as_str(), 16)
+                    .ok()
+                    .map(|mask| mask.count_ones())
+            }
+            ("inet6", "prefixlen") => fields[3].parse().ok(),
+            _ => continue,
+        };
+        if let Some(prefix) = prefix {
+            let addr = fields[1].split('%').next().unwrap_or("");
+            res.push(format!("{}/{}", addr, prefix));
+        }
+    }
+    res
+}
+
+/// Reads the resource usage of a jail with `rctl -u`
+fn rctl_usage(config: &Config, uuid: &str) -> Result<BTreeMap<String, u64>, Box<Error>> {
+    let mut filter = String::from("jail:");
+    filter.push_str(uuid);
+    let output = config.executor.run(RCTL, &["-u", filter.as_str()])?;
+    let mut res = BTreeMap::new();
+    if !output.success() {
+        warn!("failed to read resource usage"; "vm" => uuid, "stderr" => output.stderr);
+        return Ok(res);
+    }
+    for line in output.stdout.lines() {
+        let mut kv = line.splitn(2, '=');
+        if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
+            if let Ok(value) = value.trim().parse::<u64>() {
+                res.insert(String::from(key), value);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Splits a rule like `jail:<uuid>:memoryuse:deny=1024M`
+fn parse_rule(rule: &str) -> Option<RctlInfo> {
+    let parts: Vec<&str> = rule.split(':').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let mut action = parts[3].splitn(2, '=');
+    match (action.next(), action.next()) {
+        (Some(action), Some(limit)) => Some(RctlInfo {
+            resource: String::from(parts[2]),
+            action: String::from(action),
+            limit: String::from(limit),
+            usage: None,
+        }),
+        _ => None,
+    }
+}
+
+/// Filesystems mounted at or below `root`, read from `mount -p`
+fn mounts(config: &Config, root: &str) -> Result<Vec<MountInfo>, Box<Error>> {
+    let output = config.executor.run(MOUNT, &["-p"])?;
+    let mut res = Vec::new();
+    if !output.success() {
+        warn!("failed to read mounts"; "stderr" => output.stderr);
+        return Ok(res);
+    }
+    let mut prefix = String::from(root);
+    prefix.push('/');
+    for line in output.stdout.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        if fields[1] == root || fields[1].starts_with(prefix.as_str()) {
+            res.push(MountInfo {
+                source: String::from(fields[0]),
+                target: String::from(fields[1]),
+                fstype: String::from(fields[2]),
+            });
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use jails::info::*;
+
+    #[test]
+    fn parameters() {
+        let params = parse_parameters(
+            r#"jid=3 name=web path=/zroot/jails/web host.hostname="my \"host\"" vnet=new persist"#,
+        );
+        assert_eq!(Some(&String::from("3")), params.get("jid"));
+        assert_eq!(Some(&String::from("my \"host\"")), params.get("host.hostname"));
+        assert_eq!(Some(&String::from("new")), params.get("vnet"));
+        assert_eq!(Some(&String::from("true")), params.get("persist"));
+    }
+
+    #[test]
+    fn addresses() {
+        let output = "net0: flags=8843<UP,BROADCAST,RUNNING,SIMPLEX,MULTICAST> metric 0 mtu 1500
+\tether 02:5a:1f:3c:00:0b
+\tinet 10.0.0.23 netmask 0xffffff00 broadcast 10.0.0.255
+\tinet6 fe80::5a:1fff:fe3c:b%net0 prefixlen 64 scopeid 0x2
+\tinet6 fd00::23 prefixlen 64 autoconf
+\tnd6 options=23<PERFORMNUD,ACCEPT_RTADV,AUTO_LINKLOCAL>";
+        assert_eq!(
+            vec!["10.0.0.23/24", "fe80::5a:1fff:fe3c:b/64", "fd00::23/64"],
+            parse_addresses(output)
+        );
+    }
+
+    #[test]
+    fn rule() {
+        assert_eq!(
+            Some(RctlInfo {
+                resource: String::from("memoryuse"),
+                action: String::from("deny"),
+                limit: String::from("1024M"),
+                usage: None,
+            }),
+            parse_rule("jail:fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53:memoryuse:deny=1024M")
+        );
+        assert_eq!(None, parse_rule("-a"));
+    }
+}
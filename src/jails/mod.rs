@@ -1,14 +1,27 @@
 //! Wrapper around the freebsd jail commands
 
+use std::fs::{self, File};
+use std::io::Write;
 use std::error::Error;
+use std::fmt;
 use errors::GenericError;
 use std::collections::HashMap;
-use std::process::Command;
-use jail_config::IFace;
+use jail_config::{IFace, NIC};
 use config::Config;
 use uuid::Uuid;
-use jdb::IdxEntry;
+use jdb::{JDB, IdxEntry, JailState};
+use metadata;
 use jail_config::JailConfig;
+use serde_json::{self, Value};
+use images;
+use zfs;
+
+mod filesystems;
+mod hotplug;
+mod info;
+mod user_script;
+pub use self::filesystems::delegated_dataset;
+pub use self::user_script::run as run_user_script;
 
 #[derive(Debug)]
 /// Basic information about a ZFS dataset
@@ -24,28 +37,75 @@ struct CreateArgs {
     ifs: Vec<IFace>,
 }
 
-#[cfg(target_os = "freebsd")]
 static UMOUNT: &'static str = "umount";
-#[cfg(target_os = "freebsd")]
 static MOUNT: &'static str = "mount";
-#[cfg(target_os = "freebsd")]
 static RCTL: &'static str = "rctl";
-#[cfg(target_os = "freebsd")]
 static JAIL: &'static str = "jail";
-#[cfg(not(target_os = "freebsd"))]
-static MOUNT: &'static str = "echo";
-#[cfg(not(target_os = "freebsd"))]
-static UMOUNT: &'static str = "echo";
-#[cfg(not(target_os = "freebsd"))]
-static RCTL: &'static str = "echo";
-#[cfg(not(target_os = "freebsd"))]
-static JAIL: &'static str = "echo";
-
-#[cfg(target_os = "freebsd")]
+static JLS: &'static str = "jls";
 static IFCONFIG: &'static str = "/sbin/ifconfig";
-#[cfg(not(target_os = "freebsd"))]
-static IFCONFIG: &'static str = "echo";
+static JEXEC: &'static str = "jexec";
+static TIMEOUT: &'static str = "timeout";
+static ENV: &'static str = "/usr/bin/env";
+/// Exit code of timeout(1) when the command ran out of time
+static TIMEOUT_EXPIRED: i32 = 124;
+
+/// How a jail was shut down
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Shutdown {
+    /// `rc.shutdown` completed in time
+    Clean,
+    /// `rc.shutdown` did not complete within the stop timeout
+    Timeout,
+    /// `rc.shutdown` failed
+    Failed,
+    /// Shutdown was skipped and the jail killed right away
+    Forced,
+}
+
+impl fmt::Display for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Shutdown::Clean => "clean",
+            Shutdown::Timeout => "timed out, killed",
+            Shutdown::Failed => "shutdown failed, killed",
+            Shutdown::Forced => "forced",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Changes of an update, split into the ones that took effect on the
+/// running jail and the ones that only apply after a restart
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    /// changes in effect right away
+    pub live: Vec<String>,
+    /// changes that need a restart of the jail
+    pub restart: Vec<String>,
+}
 
+/// A change to a running jail along with what is needed to undo it
+#[allow(clippy::large_enum_variant)]
+enum Step {
+    /// quota was changed, holds the previous quota
+    Quota(u64),
+    /// a rctl rule was added, changed or removed, holds the previous
+    /// and the new rule of the resource
+    Rctl(Option<String>, Option<String>),
+    /// a NIC was added
+    Plugged(NIC),
+    /// a NIC was removed
+    Unplugged(NIC),
+    /// a NIC was reconfigured, holds the previous and the new config
+    Configured(NIC, NIC),
+    /// static routes were changed, holds the script to change them back
+    Routes(String),
+    /// resolv.conf was rewritten, holds the script to restore it
+    Resolvers(String),
+    /// a dataset was made indestructible or released, holds the dataset
+    /// and the previous setting
+    Indestructible(String, bool),
+}
 
 /// Jail config
 pub struct Jail<'a> {
@@ -60,50 +120,287 @@ pub struct Jail<'a> {
 }
 
 impl<'a> Jail<'a> {
+    /// JSON representation of the jail, its config along with the
+    /// recorded state
+    pub fn json(&self) -> Result<Value, Box<Error>> {
+        let mut value = serde_json::to_value(&self.config)?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert(String::from("state"), Value::from(self.idx.state.to_string()));
+        }
+        Ok(value)
+    }
+
+    /// Fields that are derived from the runtime or other sources instead
+    /// of being stored in the config, `None` if `field` is not one.
+    pub fn derived(&self, config: &Config, field: &str) -> Option<Value> {
+        let value = match field {
+            "type" => {
+                if self.config.brand == "lx-jail" {
+                    Value::from("LX")
+                } else {
+                    Value::from("OS")
+                }
+            }
+            "ram" => Value::from(self.config.max_physical_memory),
+            "jls" => {
+                match self.outer {
+                    Some(_) => Value::from("running"),
+                    None => Value::from("stopped"),
+                }
+            }
+            "jid" => {
+                match self.outer {
+                    Some(outer) => Value::from(outer.id),
+                    None => Value::Null,
+                }
+            }
+            "primary_ip" => {
+                match self.config.nics.iter().find(|nic| nic.primary) {
+                    Some(nic) => Value::from(nic.ip.clone()),
+                    None => Value::Null,
+                }
+            }
+            "image_name" => {
+                match images::name(config, &self.config.image_uuid) {
+                    Some(name) => Value::from(name),
+                    None => Value::Null,
+                }
+            }
+            "zfs_used" => {
+                match zfs::get(config, self.idx.root.as_str()) {
+                    Ok(entry) => Value::from(entry.used),
+                    Err(_) => Value::Null,
+                }
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Records a lifecycle transition for this jail in the index and
+    /// returns the previous state.
+    pub fn transition(&self, config: &Config, state: JailState) -> Result<JailState, Box<Error>> {
+        let mut db = JDB::open(config)?;
+        db.set_state(&self.idx.uuid, state)
+    }
+
+    /// Brings the index in line with the OS after crashes or host
+    /// reboots: jails the index says are active but that are not running
+    /// are marked as failed, running jails are marked as running.
+    pub fn reconcile(&self, config: &Config) -> Result<(), Box<Error>> {
+        match (self.outer.is_some(), self.idx.state) {
+            (false, JailState::Starting) |
+            (false, JailState::Running) |
+            (false, JailState::Stopping) => {
+                warn!("Jail is not running, marking it as failed";
+                      "vm" => self.idx.uuid.hyphenated().to_string(),
+                      "state" => self.idx.state.to_string());
+                self.transition(config, JailState::Failed)?;
+                Ok(())
+            }
+            (true, JailState::Stopped) |
+            (true, JailState::Starting) |
+            (true, JailState::Stopping) |
+            (true, JailState::Failed) => {
+                warn!("Jail is running, marking it as running";
+                      "vm" => self.idx.uuid.hyphenated().to_string(),
+                      "state" => self.idx.state.to_string());
+                let mut db = JDB::open(config)?;
+                db.force_state(&self.idx.uuid, JailState::Running)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// starts a jail
     pub fn start(&self, config: &Config) -> Result<i32, Box<Error>> {
-        self.set_rctl()?;
-        self.mount_devfs()?;
+        self.reconcile(config)?;
+        self.transition(config, JailState::Starting)?;
+        match self.boot(config) {
+            Ok(res) => {
+                self.transition(config, JailState::Running)?;
+                // a failing script is recorded but does not fail the start
+                if self.user_script_due() {
+                    if let Err(e) = user_script::start(config, &self.idx.uuid) {
+                        warn!("Failed to start user-script"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                              "error" => e.to_string());
+                    }
+                }
+                Ok(res)
+            }
+            Err(e) => {
+                crit!("Failed to start jail"; "vm" => self.idx.uuid.hyphenated().to_string());
+                let _ = self.transition(config, JailState::Failed);
+                Err(e)
+            }
+        }
+    }
+
+    /// stops a jail, unless `force` is set the jail is given the
+    /// configured stop timeout to run `rc.shutdown` before it is killed.
+    /// A forced stop works from any state but deleting.
+    pub fn stop(&self, config: &Config, force: bool) -> Result<Shutdown, Box<Error>> {
+        self.reconcile(config)?;
+        let shutdown = if force {
+            if self.idx.state == JailState::Deleting {
+                return Err(GenericError::bx("VM is being deleted"));
+            }
+            let mut db = JDB::open(config)?;
+            db.force_state(&self.idx.uuid, JailState::Stopping)?;
+            Shutdown::Forced
+        } else {
+            self.transition(config, JailState::Stopping)?;
+            self.shutdown(config)
+        };
+        match self.halt(config) {
+            Ok(_) => {
+                self.transition(config, JailState::Stopped)?;
+                Ok(shutdown)
+            }
+            Err(e) => {
+                let _ = self.transition(config, JailState::Failed);
+                Err(e)
+            }
+        }
+    }
+
+    fn boot(&self, config: &Config) -> Result<i32, Box<Error>> {
+        self.set_rctl(config)?;
+        self.mount_devfs(config)?;
         
         if self.config.brand == "lx-jail" {
-            self.mount_lxfs()?;
+            self.mount_lxfs(config)?;
         }
-        
+        self.mount_filesystems(config)?;
+
+        // the jail boots without metadata rather than not at all
+        if let Err(e) = metadata::start(config, &self.idx.uuid) {
+            warn!("failed to start metadata service"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                  "error" => e.to_string());
+        }
+
         let CreateArgs { args, ifs } = create_args(config, self)?;
         debug!("Start jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
-        let id = start_jail(&self.idx.uuid, args)?;
+        let id = start_jail(config, &self.idx.uuid, args)?;
         let id_str = id.to_string();
         let mut jprefix = String::from("j");
         jprefix.push_str(id_str.as_str());
         jprefix.push(':');
         for iface in ifs.iter() {
-            let mut epair = String::from(iface.epair.clone());
+            let mut epair = iface.epair.clone();
             epair.push('a');
             let mut target_name = jprefix.clone();
             target_name.push_str(iface.iface.as_str());
-            let args = vec![epair, String::from("name"), target_name];
+            let args = vec![epair.as_str(), "name", target_name.as_str()];
             debug!("renaiming epair"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
-            let output = Command::new(IFCONFIG).args(args.clone()).output().expect(
-                "ifconfig failed",
-            );
-            if !output.status.success() {
+            let output = config.executor.run(IFCONFIG, &args)?;
+            if !output.success() {
                 crit!("failed to rename interface"; "vm" => self.idx.uuid.hyphenated().to_string());
             }
         }
+        if self.config.delegate_dataset {
+            self.attach_delegated(config)?;
+        }
         Ok(0)
     }
 
-    /// stops a jail
-    pub fn stop(&self) -> Result<i32, Box<Error>> {
+    /// Shell used for the console, taken from the `shells` setting for
+    /// the brand and otherwise the brands default.
+    pub fn shell(&self, config: &Config) -> String {
+        if let Some(shell) = config.settings.shells.get(&self.config.brand) {
+            return shell.clone();
+        }
+        match self.config.brand.as_str() {
+            "lx-jail" => String::from("/bin/sh"),
+            _ => String::from("/bin/csh"),
+        }
+    }
+
+    /// Runs a command in the inner jail attached to the current
+    /// terminal, optionally as `user` and with additional `KEY=VALUE`
+    /// environment variables, and returns its exit code.
+    pub fn exec(
+        &self,
+        config: &Config,
+        user: Option<&str>,
+        env: &[&str],
+        cmd: &[&str],
+    ) -> Result<i32, Box<Error>> {
+        let inner = match self.inner {
+            Some(inner) => inner.id.to_string(),
+            None => return Err(GenericError::bx("VM is not running")),
+        };
+        if cmd.is_empty() {
+            return Err(GenericError::bx("No command given"));
+        }
+        if let Some(var) = env.iter().find(|e| !e.contains('=') || e.starts_with('=')) {
+            return Err(GenericError::bx(
+                format!("Invalid environment variable: {}", var).as_str(),
+            ));
+        }
+        let mut args: Vec<&str> = Vec::new();
+        if let Some(user) = user {
+            args.push("-U");
+            args.push(user);
+        }
+        args.push(inner.as_str());
+        if !env.is_empty() {
+            args.push(ENV);
+            args.extend_from_slice(env);
+        }
+        args.extend_from_slice(cmd);
+        debug!("Executing in jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
+        config.executor.interactive(JEXEC, &args)
+    }
+
+    /// Runs `rc.shutdown` in the inner jail and waits for it up to the
+    /// stop timeout
+    fn shutdown(&self, config: &Config) -> Shutdown {
+        let inner = match self.inner {
+            Some(inner) => inner.id.to_string(),
+            None => {
+                warn!("No inner jail to shut down"; "vm" => self.idx.uuid.hyphenated().to_string());
+                return Shutdown::Failed;
+            }
+        };
+        let timeout = config.settings.stop_timeout.to_string();
+        let args = vec![timeout.as_str(), JEXEC, inner.as_str(), "/bin/sh", "/etc/rc.shutdown"];
+        debug!("Shutting down jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
+        match config.executor.run(TIMEOUT, &args) {
+            Ok(ref output) if output.success() => Shutdown::Clean,
+            Ok(ref output) if output.code == TIMEOUT_EXPIRED => {
+                warn!("Jail did not shut down in time"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                      "timeout" => config.settings.stop_timeout);
+                Shutdown::Timeout
+            }
+            Ok(output) => {
+                warn!("Jail shutdown failed"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                      "code" => output.code, "stderr" => output.stderr);
+                Shutdown::Failed
+            }
+            Err(e) => {
+                warn!("Jail shutdown failed"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                      "error" => e.to_string());
+                Shutdown::Failed
+            }
+        }
+    }
+
+    /// Kills all processes left in the jail and tears down devfs, rctl
+    /// rules and interfaces
+    fn halt(&self, config: &Config) -> Result<i32, Box<Error>> {
         debug!("Dleting jail"; "vm" => self.idx.uuid.hyphenated().to_string());
-        let output = Command::new(JAIL)
-            .args(&["-r", self.idx.uuid.hyphenated().to_string().as_str()])
-            .output()
-            .expect("zfs list failed");
-        if !output.status.success() {
+        let output = config.executor.run(
+            JAIL,
+            &["-r", self.idx.uuid.hyphenated().to_string().as_str()],
+        )?;
+        if !output.success() {
             crit!("Failed to stop jail"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not stop jail"));
         }
+        // the rest is torn down even if a filesystem stays mounted
+        let umounted = self.umount_filesystems(config);
 
         let mut devfs = String::from("/");
         devfs.push_str(self.idx.root.as_str());
@@ -111,10 +408,8 @@ impl<'a> Jail<'a> {
         let devfs_args = vec![devfs.as_str()];
 
         debug!("un mounting devfs in outer jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => devfs_args.clone().join(" "));
-        let output = Command::new(UMOUNT).args(devfs_args).output().expect(
-            "failed to mount devfs in outer jail",
-        );
-        if !output.status.success() {
+        let output = config.executor.run(UMOUNT, &devfs_args)?;
+        if !output.success() {
             crit!("failed to mount devfs in outer jail"; "vm" => self.idx.uuid.hyphenated().to_string());
         }
 
@@ -124,14 +419,13 @@ impl<'a> Jail<'a> {
         let devfs_args = vec![devfs.as_str()];
 
         debug!("un mounting devfs in inner jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" =>devfs_args.clone().join(" "));
-        let output = Command::new(UMOUNT).args(devfs_args).output().expect(
-            "failed to mount devfs in inner jail",
-        );
-        if !output.status.success() {
+        let output = config.executor.run(UMOUNT, &devfs_args)?;
+        if !output.success() {
             crit!("failed to mount devfs in inner jail"; "vm" => self.idx.uuid.hyphenated().to_string());
         }
 
-        let _ = self.remove_rctl();
+        let _ = self.remove_rctl(config);
+        let _ = metadata::stop(config, &self.idx.uuid);
         match self.outer {
             Some(outer) => {
                 let id_str = outer.id.to_string();
@@ -141,12 +435,10 @@ impl<'a> Jail<'a> {
                 for nic in self.config.nics.clone() {
                     let mut target_name = jprefix.clone();
                     target_name.push_str(nic.interface.as_str());
-                    let args = vec![target_name, String::from("destroy")];
+                    let args = vec![target_name.as_str(), "destroy"];
                     debug!("renaiming epair"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => args.clone().join(" "));
-                    let output = Command::new(IFCONFIG).args(args.clone()).output().expect(
-                        "ifconfig failed",
-                    );
-                    if !output.status.success() {
+                    let output = config.executor.run(IFCONFIG, &args)?;
+                    if !output.success() {
                         crit!("failed to rename interface"; "vm" => self.idx.uuid.hyphenated().to_string());
                     }
                 }
@@ -156,34 +448,31 @@ impl<'a> Jail<'a> {
             }
         }
 
-        Ok(0)
+        umounted
     }
 
-    fn set_rctl(&self) -> Result<i32, Box<Error>> {
+    fn set_rctl(&self, config: &Config) -> Result<i32, Box<Error>> {
         let limits = self.config.rctl_limits();
         debug!("Setting jail limits"; "vm" => self.idx.uuid.hyphenated().to_string(), "limits" => limits.clone().join(" "));
-        let output = Command::new(RCTL).args(limits.clone()).output().expect(
-            "limit failed",
-        );
-        if !output.status.success() {
+        let limit_args: Vec<&str> = limits.iter().map(|l| l.as_str()).collect();
+        let output = config.executor.run(RCTL, &limit_args)?;
+        if !output.success() {
             crit!("failed to set resource limits"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not set jail limits"));
         }
         Ok(0)
     }
 
-    fn mount_devfs(&self) -> Result<i32, Box<Error>> {
+    fn mount_devfs(&self, config: &Config) -> Result<i32, Box<Error>> {
         let mut devfs = String::from("/");
         devfs.push_str(self.idx.root.as_str());
         devfs.push_str("/root/dev");
         let devfs_args = vec!["-t", "devfs", "devfs", devfs.as_str()];
 
         debug!("mounting devfs in outer jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" =>devfs_args.clone().join(" "));
-        let output = Command::new(MOUNT).args(devfs_args).output().expect(
-            "failed to mount devfs in outer jail",
-        );
+        let output = config.executor.run(MOUNT, &devfs_args)?;
 
-        if !output.status.success() {
+        if !output.success() {
             crit!("failed to mount ounter devfs"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could mount outer devfs"));
         }
@@ -194,17 +483,15 @@ impl<'a> Jail<'a> {
         let devfs_args = vec!["-t", "devfs", "devfs", devfs.as_str()];
 
         debug!("mounting devfs in inner jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" =>devfs_args.clone().join(" "));
-        let output = Command::new(MOUNT).args(devfs_args).output().expect(
-            "failed to mount devfs in inner jail",
-        );
-        if !output.status.success() {
+        let output = config.executor.run(MOUNT, &devfs_args)?;
+        if !output.success() {
             crit!("failed to mount inner devfs"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not remove resource limits"));
         }
 
         Ok(0)
     }
-    fn mount_lxfs(&self) -> Result<i32, Box<Error>> {
+    fn mount_lxfs(&self, config: &Config) -> Result<i32, Box<Error>> {
         
         let mut linprocfs = String::from("/");
         linprocfs.push_str(self.idx.root.as_str());
@@ -212,10 +499,8 @@ impl<'a> Jail<'a> {
         let linprocfs_args = vec!["-t", "linprocfs", "linprocfs", linprocfs.as_str()];
 
         debug!("mounting linprocfs in inner jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" =>linprocfs_args.clone().join(" "));
-        let output = Command::new(MOUNT).args(linprocfs_args).output().expect(
-            "failed to mount linprocfs in inner jail",
-        );
-        if !output.status.success() {
+        let output = config.executor.run(MOUNT, &linprocfs_args)?;
+        if !output.success() {
             crit!("failed to mount inner linprocfs"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not remove resource limits"));
         }
@@ -226,10 +511,8 @@ impl<'a> Jail<'a> {
         let linsysfs_args = vec!["-t", "linsysfs", "linsysfs", linsysfs.as_str()];
 
         debug!("mounting linsysfs in inner jail"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" =>linsysfs_args.clone().join(" "));
-        let output = Command::new(MOUNT).args(linsysfs_args).output().expect(
-            "failed to mount linsysfs in inner jail",
-        );
-        if !output.status.success() {
+        let output = config.executor.run(MOUNT, &linsysfs_args)?;
+        if !output.success() {
             crit!("failed to mount inner linsysfs"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not remove resource limits"));
         }
@@ -237,16 +520,199 @@ impl<'a> Jail<'a> {
         Ok(0)
     }
 
-    fn remove_rctl(&self) -> Result<i32, Box<Error>> {
+    /// Applies the difference between the current config and `new` to
+    /// the jail, resource limits change right away while it is running.
+    pub fn apply_update(&self, config: &Config, new: &JailConfig) -> Result<Applied, Box<Error>> {
+        self.apply_changes(config, &self.config, new)
+    }
+
+    /// Undoes `apply_update`, used when the update could not be saved
+    pub fn revert_update(&self, config: &Config, new: &JailConfig) -> Result<Applied, Box<Error>> {
+        self.apply_changes(config, new, &self.config)
+    }
+
+    fn apply_changes(
+        &self,
+        config: &Config,
+        from: &JailConfig,
+        to: &JailConfig,
+    ) -> Result<Applied, Box<Error>> {
+        let mut applied = Applied::default();
+        let mut done = Vec::new();
+        if let Err(e) = self.apply_steps(config, from, to, &mut applied, &mut done) {
+            crit!("Failed to apply update, rolling back"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                  "error" => e.to_string());
+            for step in done.iter().rev() {
+                if let Err(undo) = self.undo(config, step) {
+                    crit!("Failed to roll back"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                          "error" => undo.to_string());
+                }
+            }
+            return Err(e);
+        }
+        Ok(applied)
+    }
+
+    fn apply_steps(
+        &self,
+        config: &Config,
+        from: &JailConfig,
+        to: &JailConfig,
+        applied: &mut Applied,
+        done: &mut Vec<Step>,
+    ) -> Result<(), Box<Error>> {
+        if from.quota != to.quota {
+            set_quota(config, self.idx.root.as_str(), to.quota)?;
+            done.push(Step::Quota(from.quota));
+            applied.live.push(format!("quota={}G", to.quota));
+        }
+        let mut protected = vec![
+            (
+                "indestructible_zoneroot",
+                self.idx.root.clone(),
+                from.indestructible_zoneroot,
+                to.indestructible_zoneroot,
+            ),
+        ];
+        if to.delegate_dataset {
+            protected.push((
+                "indestructible_delegated",
+                delegated_dataset(self.idx.root.as_str()),
+                from.indestructible_delegated,
+                to.indestructible_delegated,
+            ));
+        }
+        for (field, dataset, was, is) in protected {
+            let (was, is) = (was == Some(true), is == Some(true));
+            if was == is {
+                continue;
+            }
+            set_indestructible(config, dataset.as_str(), is)?;
+            applied.live.push(format!("{}={}", field, is));
+            done.push(Step::Indestructible(dataset, was));
+        }
+        if self.outer.is_none() {
+            return Ok(());
+        }
+        self.apply_rctl(config, &from.rctl_limits(), &to.rctl_limits(), applied, done)?;
+        if from.hostname != to.hostname {
+            applied.restart.push(String::from("hostname"));
+        }
+        for nic in from.nics.iter() {
+            match to.nics.iter().find(|n| n.mac == nic.mac) {
+                None => {
+                    self.unplug_nic(config, nic)?;
+                    done.push(Step::Unplugged(nic.clone()));
+                    applied.live.push(format!("remove nic {}", nic.interface));
+                }
+                Some(new) if new == nic => (),
+                Some(new) if hotplug::live_change(nic, new) => {
+                    self.configure_nic(config, nic, new)?;
+                    done.push(Step::Configured(nic.clone(), new.clone()));
+                    applied.live.push(format!("update nic {}", nic.interface));
+                }
+                Some(_) => applied.restart.push(format!("update nic {}", nic.interface)),
+            }
+        }
+        for nic in to.nics.iter() {
+            if !from.nics.iter().any(|n| n.mac == nic.mac) {
+                self.plug_nic(config, nic)?;
+                done.push(Step::Plugged(nic.clone()));
+                applied.live.push(format!("add nic {}", nic.interface));
+            }
+        }
+        if from.routes != to.routes {
+            self.change_routes(config, hotplug::route_changes(from, to).as_str())?;
+            done.push(Step::Routes(hotplug::route_changes(to, from)));
+            applied.live.push(String::from("routes"));
+        }
+        if to.maintain_resolvers && !to.resolvers.is_empty() &&
+            (from.resolvers != to.resolvers || from.dns_domain != to.dns_domain)
+        {
+            self.write_resolvers(config, to)?;
+            done.push(Step::Resolvers(from.resolv_conf_script()));
+            applied.live.push(String::from("resolvers"));
+        }
+        Ok(())
+    }
+
+    /// Reverts a step of an update that was already applied
+    fn undo(&self, config: &Config, step: &Step) -> Result<i32, Box<Error>> {
+        match *step {
+            Step::Quota(quota) => set_quota(config, self.idx.root.as_str(), quota),
+            Step::Rctl(Some(ref old), _) => self.replace_rctl(config, old.as_str()),
+            Step::Rctl(None, Some(ref new)) => self.unset_rctl(config, new.as_str()),
+            Step::Rctl(None, None) => Ok(0),
+            Step::Unplugged(ref nic) => self.plug_nic(config, nic),
+            Step::Configured(ref old, ref new) => self.configure_nic(config, new, old),
+            Step::Plugged(ref nic) => self.unplug_nic(config, nic),
+            Step::Routes(ref script) |
+            Step::Resolvers(ref script) => self.run_in_outer(config, script.as_str()),
+            Step::Indestructible(ref dataset, was) => set_indestructible(config, dataset.as_str(), was),
+        }
+    }
+
+    /// Sets, replaces and removes rctl rules so the rules `from` become
+    /// the rules `to`
+    fn apply_rctl(
+        &self,
+        config: &Config,
+        from: &[String],
+        to: &[String],
+        applied: &mut Applied,
+        done: &mut Vec<Step>,
+    ) -> Result<(), Box<Error>> {
+        for (old, new) in rctl_diff(from, to) {
+            let change = match new {
+                Some(ref rule) => {
+                    self.replace_rctl(config, rule.as_str())?;
+                    rule.splitn(3, ':').nth(2).unwrap_or("").replace(":deny", "")
+                }
+                None => {
+                    let rule = old.clone().unwrap_or_default();
+                    self.unset_rctl(config, rule.as_str())?;
+                    format!("{} removed", rctl_resource(rule.as_str()))
+                }
+            };
+            done.push(Step::Rctl(old, new));
+            applied.live.push(change);
+        }
+        Ok(())
+    }
+
+    /// Replaces the rule for the resource of `rule` with `rule`
+    fn replace_rctl(&self, config: &Config, rule: &str) -> Result<i32, Box<Error>> {
+        debug!("replacing rctl limit"; "vm" => self.idx.uuid.hyphenated().to_string(), "rule" => rule);
+        self.unset_rctl(config, rule)?;
+        let output = config.executor.run(RCTL, &["-a", rule])?;
+        if !output.success() {
+            crit!("failed to set resource limit"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                  "rule" => rule);
+            return Err(GenericError::bx("Could not set resource limit"));
+        }
+        Ok(0)
+    }
+
+    /// Removes the rule for the resource of `rule`
+    fn unset_rctl(&self, config: &Config, rule: &str) -> Result<i32, Box<Error>> {
+        let filter = rctl_filter(rule);
+        let output = config.executor.run(RCTL, &["-r", filter.as_str()])?;
+        if !output.success() {
+            crit!("failed to remove resource limit"; "vm" => self.idx.uuid.hyphenated().to_string(),
+                  "filter" => filter.clone());
+            return Err(GenericError::bx("Could not remove resource limit"));
+        }
+        Ok(0)
+    }
+
+    fn remove_rctl(&self, config: &Config) -> Result<i32, Box<Error>> {
         let mut prefix = String::from("jail:");
         prefix.push_str(self.idx.uuid.hyphenated().to_string().as_str());
         let limit_args = vec!["-r", prefix.as_str()];
         debug!("removing rctl limits"; "vm" => self.idx.uuid.hyphenated().to_string(), "args" => limit_args.clone().join(" "));
-        let output = Command::new(RCTL).args(limit_args).output().expect(
-            "rctl failed",
-        );
+        let output = config.executor.run(RCTL, &limit_args)?;
 
-        if !output.status.success() {
+        if !output.success() {
             crit!("failed to remove resource limits"; "vm" => self.idx.uuid.hyphenated().to_string());
             return Err(GenericError::bx("Could not remove resource limits"));
         }
@@ -254,29 +720,126 @@ impl<'a> Jail<'a> {
     }
 }
 
-#[cfg(not(target_os = "freebsd"))]
-fn start_jail(_uuid: &Uuid, _args: Vec<String>) -> Result<u64, Box<Error>> {
-    Ok(42)
+/// `jail:<uuid>:<resource>` part of a `jail:<uuid>:<resource>:<action>=<amount>`
+/// rule
+fn rctl_filter(rule: &str) -> String {
+    let filter: Vec<&str> = rule.splitn(4, ':').take(3).collect();
+    filter.join(":")
+}
+
+/// Resource a rule limits
+fn rctl_resource(rule: &str) -> &str {
+    rule.split(':').nth(2).unwrap_or("")
+}
+
+/// Resources whose rule differs between `from` and `to` as pairs of the
+/// old and the new rule, `None` if there is no rule for the resource on
+/// that side
+fn rctl_diff(from: &[String], to: &[String]) -> Vec<(Option<String>, Option<String>)> {
+    let find = |rules: &[String], rule: &str| {
+        rules
+            .iter()
+            .find(|r| *r != "-a" && rctl_filter(r) == rctl_filter(rule))
+            .cloned()
+    };
+    let mut diff = Vec::new();
+    for rule in to.iter().filter(|r| *r != "-a" && !from.contains(r)) {
+        diff.push((find(from, rule), Some(rule.clone())));
+    }
+    for rule in from.iter().filter(|r| *r != "-a") {
+        if find(to, rule).is_none() {
+            diff.push((Some(rule.clone()), None));
+        }
+    }
+    diff
+}
+
+/// Sets the quota of a jails root dataset in GB, 0 removes the quota.
+/// A quota below what the dataset already uses is refused.
+pub fn set_quota(config: &Config, root: &str, quota: u64) -> Result<i32, Box<Error>> {
+    let value = if quota == 0 {
+        String::from("none")
+    } else {
+        let used = zfs::get(config, root)?.used;
+        let bytes = quota * 1024 * 1024 * 1024;
+        if used > bytes {
+            return Err(GenericError::bx(
+                format!(
+                    "quota of {}G is less than the {} bytes already used",
+                    quota,
+                    used
+                ).as_str(),
+            ));
+        }
+        format!("{}G", quota)
+    };
+    zfs::set_property(config, root, "quota", value.as_str())
+}
+
+/// Snapshot held to keep an indestructible dataset from being destroyed
+static INDESTRUCTIBLE_SNAPSHOT: &'static str = "indestructible";
+/// Tag of the hold on the snapshot, the same SmartOS uses
+static INDESTRUCTIBLE_HOLD: &'static str = "do_not_destroy";
+
+/// Makes a dataset indestructible or releases it again. A held snapshot
+/// is kept of indestructible datasets so even `zfs destroy -r` fails.
+pub fn set_indestructible(
+    config: &Config,
+    dataset: &str,
+    indestructible: bool,
+) -> Result<i32, Box<Error>> {
+    let snapshot = format!("{}@{}", dataset, INDESTRUCTIBLE_SNAPSHOT);
+    let present = zfs::is_present(config, snapshot.as_str());
+    if indestructible && !present {
+        zfs::snapshot(config, dataset, INDESTRUCTIBLE_SNAPSHOT)?;
+        if let Err(e) = zfs::hold(config, INDESTRUCTIBLE_HOLD, snapshot.as_str()) {
+            let _ = zfs::destroy(config, snapshot.as_str());
+            return Err(e);
+        }
+    } else if !indestructible && present {
+        zfs::release(config, INDESTRUCTIBLE_HOLD, snapshot.as_str())?;
+        zfs::destroy(config, snapshot.as_str())?;
+    }
+    Ok(0)
 }
 
-#[cfg(target_os = "freebsd")]
-fn start_jail(uuid: &Uuid, args: Vec<String>) -> Result<u64, Box<Error>> {
-    let output = Command::new(JAIL).args(args.clone()).output().expect(
-        "jail failed",
-    );
-    let reply = String::from_utf8_lossy(&output.stdout).into_owned();
-    if output.status.success() {
+/// Writes resolv.conf into a freshly created jail root, nothing is written
+/// without resolvers
+pub fn write_resolv_conf(root: &str, jail: &JailConfig) -> Result<i32, Box<Error>> {
+    if jail.resolvers.is_empty() {
+        return Ok(0);
+    }
+    let path = format!("/{}/root/jail/etc/resolv.conf", root);
+    debug!("writing resolv.conf"; "vm" => jail.uuid.hyphenated().to_string(), "path" => path.clone());
+    // the image may ship resolv.conf as a symlink, replace it instead of
+    // writing to wherever it points
+    if let Ok(meta) = fs::symlink_metadata(path.as_str()) {
+        if meta.file_type().is_symlink() {
+            fs::remove_file(path.as_str())?;
+        }
+    }
+    let mut file = File::create(path.as_str())?;
+    file.write_all(jail.resolv_conf().as_bytes())?;
+    Ok(0)
+}
+
+fn start_jail(config: &Config, uuid: &Uuid, args: Vec<String>) -> Result<u64, Box<Error>> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let output = config.executor.run(JAIL, &args)?;
+    if output.success() {
         // the Jail command has a bug that it will not honor -q
         // so everything but the first line might be garbage we have to
         // ignore.
-        let mut lines = reply.lines();
-        let first = lines.next().unwrap();
+        let mut lines = output.stdout.lines();
+        let first = lines.next().ok_or_else(
+            || GenericError::bx("jail did not return an id"),
+        )?;
         // this seems odd but we guarnatee our ID is a int this way
-        let id: u64 = first.trim().parse().unwrap();
+        let id: u64 = first.trim().parse()?;
         Ok(id)
     } else {
         crit!("Failed to start jail"; "vm" => uuid.hyphenated().to_string().as_str());
-        Err(GenericError::bx(reply.as_str()))
+        Err(GenericError::bx(output.stderr.as_str()))
     }
 }
 
@@ -314,6 +877,12 @@ fn create_args(config: &Config, jail: &Jail) -> Result<CreateArgs, Box<Error>> {
     // for nested jails
     args.push(String::from("allow.raw_sockets"));
     args.push(String::from("children.max=1"));
+    if jail.config.delegate_dataset {
+        // the inner jail can only get what the outer one has
+        args.push(String::from("allow.mount"));
+        args.push(String::from("allow.mount.zfs"));
+        args.push(String::from("enforce_statfs=1"));
+    }
 
 
     // let mut exec_stop = String::from("exec.stop=");
@@ -334,6 +903,13 @@ fn create_args(config: &Config, jail: &Jail) -> Result<CreateArgs, Box<Error>> {
     if !jail.config.nics.is_empty() {
         exec_start.push_str("/sbin/ifconfig lo0 127.0.0.1 up; ");
     };
+    exec_start.push_str(jail.config.routes_script().as_str());
+    for nic in jail.config.nics.iter() {
+        exec_start.push_str(nic.autoconf_script().as_str());
+    }
+    if jail.config.maintain_resolvers && !jail.config.resolvers.is_empty() {
+        exec_start.push_str(jail.config.resolv_conf_script().as_str());
+    }
     // inner jail configuration
     exec_start.push_str("jail -c");
     exec_start.push_str(" persist name=");
@@ -348,42 +924,24 @@ fn create_args(config: &Config, jail: &Jail) -> Result<CreateArgs, Box<Error>> {
     exec_start.push_str(" sysvsem=new");
     exec_start.push_str(" sysvshm=new");
     exec_start.push_str(" allow.raw_sockets");
+    if jail.config.delegate_dataset {
+        exec_start.push_str(" allow.mount allow.mount.zfs enforce_statfs=1");
+    }
     exec_start.push_str(" exec.start='sh /etc/rc'");
 
     args.push(exec_start);
     Ok(CreateArgs { args, ifs })
 }
 
-/// reads the zfs datasets in a pool
-#[cfg(target_os = "freebsd")]
-pub fn list() -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
+/// lists the running jails
+pub fn list(config: &Config) -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
     debug!("Listing jails");
-    let output = Command::new("jls")
-        .args(&["-q", "jid", "name"])
-        .output()
-        .expect("zfs list failed");
-    let reply = String::from_utf8_lossy(&output.stdout);
-    let mut res = HashMap::new();
-
-
-    for line in reply.split('\n').filter(|x| *x != "") {
-        let entry = deconstruct_entry(line)?;
-        res.insert(entry.uuid.clone(), entry);
-        ()
-    }
-    Ok(res)
-}
-
-/// Reads a dummy jail
-#[cfg(not(target_os = "freebsd"))]
-pub fn list() -> Result<HashMap<String, JailOSEntry>, Box<Error>> {
-    let reply = "1 00000000-1f3e-4b11-b0ae-8494bb6ecd52\n2 00000000-1f3e-4b11-b0ae-8494bb6ecd52.00000000-1f3e-4b11-b0ae-8494bb6ecd52\n";
+    let output = config.executor.run(JLS, &["-q", "jid", "name"])?;
     let mut res = HashMap::new();
 
-    for line in reply.split('\n').filter(|x| *x != "") {
+    for line in output.stdout.split('\n').filter(|x| !x.is_empty()) {
         let entry = deconstruct_entry(line)?;
         res.insert(entry.uuid.clone(), entry);
-        ()
     }
     Ok(res)
 }
@@ -404,3 +962,614 @@ fn deconstruct_entry(line: &str) -> Result<JailOSEntry, Box<Error>> {
         id: id,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, Simulated};
+    use jail_config::JailConfig;
+    use jdb::{JDB, JailState, ScriptResult};
+    use jails::{Applied, Shutdown, set_quota, set_indestructible};
+    use zfs;
+    use serde_json;
+    use uuid::Uuid;
+
+    fn jail_config(config: &Config) -> JailConfig {
+        let json = r#"{
+            "image_uuid": "616d4ab2-832c-11e7-9392-784f438c8d54",
+            "uuid": "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53",
+            "alias": "test", "hostname": "test",
+            "max_physical_memory": 1024, "cpu_cap": 100, "quota": 5,
+            "nics": [{"interface": "net0", "nic_tag": "admin", "ip": "192.168.1.2",
+                      "netmask": "255.255.255.0", "gateway": "192.168.1.1",
+                      "primary": true}]
+        }"#;
+        JailConfig::from_reader(config, json.as_bytes()).unwrap()
+    }
+
+    /// Inserts the test jail and marks it as provisioned
+    fn provision(config: &Config) -> Uuid {
+        let jail_config = jail_config(config);
+        let uuid = jail_config.uuid;
+        {
+            let mut db = JDB::open(config).unwrap();
+            db.insert(jail_config).unwrap();
+        }
+        let mut db = JDB::open(config).unwrap();
+        db.set_state(&uuid, JailState::Stopped).unwrap();
+        uuid
+    }
+
+    #[test]
+    fn start_stop() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert!(jail.outer.is_none());
+            jail.start(&config).unwrap();
+            // starting twice is refused
+            assert!(jail.transition(&config, JailState::Starting).is_err());
+        }
+        assert_eq!(Some(1), sim.jid(uuid_str.as_str()));
+        assert_eq!(1, sim.epair_count());
+        assert_eq!(2, sim.mounts().len());
+        assert_eq!(5, sim.rctl_rules().len());
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(JailState::Running, jail.idx.state);
+            assert_eq!(2, jail.inner.unwrap().id);
+            assert_eq!(Shutdown::Clean, jail.stop(&config, false).unwrap());
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            assert_eq!(JailState::Stopped, db.get(&uuid).unwrap().idx.state);
+        }
+        assert_eq!(None, sim.jid(uuid_str.as_str()));
+        assert_eq!(0, sim.epair_count());
+        assert!(sim.mounts().is_empty());
+        assert!(sim.rctl_rules().is_empty());
+    }
+
+    #[test]
+    fn reconcile_running() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        // the index lost track of the running jail
+        JDB::open(&config).unwrap().force_state(&uuid, JailState::Stopped).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            jail.reconcile(&config).unwrap();
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(JailState::Running, jail.idx.state);
+            assert_eq!(Shutdown::Clean, jail.stop(&config, false).unwrap());
+        }
+        assert_eq!(None, sim.jid(uuid_str.as_str()));
+        let db = JDB::open(&config).unwrap();
+        assert_eq!(JailState::Stopped, db.get(&uuid).unwrap().idx.state);
+    }
+
+    #[test]
+    fn force_stop() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        JDB::open(&config).unwrap().force_state(&uuid, JailState::Provisioning).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert!(jail.stop(&config, false).is_err());
+            assert_eq!(Shutdown::Forced, jail.stop(&config, true).unwrap());
+        }
+        assert_eq!(None, sim.jid(uuid_str.as_str()));
+        {
+            let db = JDB::open(&config).unwrap();
+            assert_eq!(JailState::Stopped, db.get(&uuid).unwrap().idx.state);
+        }
+        // a jail that is being deleted is left alone
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        JDB::open(&config).unwrap().force_state(&uuid, JailState::Deleting).unwrap();
+        let db = JDB::open(&config).unwrap();
+        assert!(db.get(&uuid).unwrap().stop(&config, true).is_err());
+        assert_eq!(Some(3), sim.jid(uuid_str.as_str()));
+    }
+
+    #[test]
+    fn user_script() {
+        let (config, _) = Simulated::new();
+        let mut jail_config = jail_config(&config);
+        jail_config.customer_metadata.insert(
+            String::from("user-script"),
+            String::from("#!/bin/sh\necho hi\n"),
+        );
+        let uuid = jail_config.uuid;
+        {
+            let mut db = JDB::open(&config).unwrap();
+            db.insert(jail_config).unwrap();
+        }
+        JDB::open(&config).unwrap().set_state(&uuid, JailState::Stopped).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert!(jail.user_script_due());
+            // the jail has to be running for the script
+            assert!(super::run_user_script(&config, &uuid).is_err());
+            jail.start(&config).unwrap();
+        }
+        // start leaves the script to a background process
+        assert!(JDB::open(&config).unwrap().get(&uuid).unwrap().user_script_due());
+        let first = super::run_user_script(&config, &uuid).unwrap().unwrap();
+        assert_eq!(Some(0), first.exit_code);
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert!(!jail.user_script_due());
+            assert_eq!(Some(Some(0)), jail.info(&config).unwrap().user_script.map(|r| r.exit_code));
+            jail.stop(&config, false).unwrap();
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        // only the first boot runs the script
+        assert_eq!(None, super::run_user_script(&config, &uuid).unwrap());
+        let db = JDB::open(&config).unwrap();
+        assert_eq!(Some(first), db.get(&uuid).unwrap().idx.user_script.clone());
+    }
+
+    #[test]
+    fn user_script_running() {
+        let (config, _) = Simulated::new();
+        let mut jail_config = jail_config(&config);
+        jail_config.customer_metadata.insert(
+            String::from("user-script"),
+            String::from("#!/bin/sh\nsleep 600\n"),
+        );
+        jail_config.user_script_every_boot = true;
+        let uuid = jail_config.uuid;
+        {
+            let mut db = JDB::open(&config).unwrap();
+            db.insert(jail_config).unwrap();
+        }
+        JDB::open(&config).unwrap().set_state(&uuid, JailState::Stopped).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        // the first run is still going
+        let started = ScriptResult::started();
+        JDB::open(&config).unwrap().set_user_script(&uuid, started.clone()).unwrap();
+        assert!(!JDB::open(&config).unwrap().get(&uuid).unwrap().user_script_due());
+        assert_eq!(None, super::run_user_script(&config, &uuid).unwrap());
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        assert_eq!(Some(started), jail.idx.user_script.clone());
+        jail.stop(&config, true).unwrap();
+    }
+
+    #[test]
+    fn user_script_install_failure() {
+        let (config, sim) = Simulated::new();
+        let mut jail_config = jail_config(&config);
+        jail_config.customer_metadata.insert(
+            String::from("user-script"),
+            String::from("#!/bin/sh\necho hi\n"),
+        );
+        let uuid = jail_config.uuid;
+        {
+            let mut db = JDB::open(&config).unwrap();
+            db.insert(jail_config).unwrap();
+        }
+        JDB::open(&config).unwrap().set_state(&uuid, JailState::Stopped).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        sim.read_only(uuid.hyphenated().to_string().as_str());
+        let result = super::run_user_script(&config, &uuid).unwrap().unwrap();
+        assert!(result.exit_code != Some(0));
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        assert_eq!(Some(result), jail.idx.user_script.clone());
+        jail.stop(&config, true).unwrap();
+    }
+
+    #[test]
+    fn filesystems() {
+        let (config, sim) = Simulated::new();
+        let mut jail_config = jail_config(&config);
+        jail_config.delegate_dataset = true;
+        jail_config.filesystems = serde_json::from_str(
+            r#"[{"type": "nullfs", "source": "/data/www", "target": "/www", "options": ["ro"]},
+                {"type": "tmpfs", "target": "/tmp"}]"#,
+        ).unwrap();
+        let uuid = jail_config.uuid;
+        let root = {
+            let mut db = JDB::open(&config).unwrap();
+            db.insert(jail_config).unwrap().root
+        };
+        JDB::open(&config).unwrap().set_state(&uuid, JailState::Stopped).unwrap();
+        let data = super::delegated_dataset(root.as_str());
+        sim.add_dataset(root.as_str());
+        zfs::create(&config, data.as_str(), &["jailed=on"]).unwrap();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            jail.start(&config).unwrap();
+        }
+        let www = format!("/{}/root/jail/www", root);
+        assert_eq!(4, sim.mounts().len());
+        assert!(sim.mounts().contains(&www));
+        // the delegated dataset keeps the root from being destroyed alone
+        assert!(zfs::destroy(&config, root.as_str()).is_err());
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(JailState::Running, jail.idx.state);
+            jail.stop(&config, false).unwrap();
+        }
+        assert!(sim.mounts().is_empty());
+    }
+
+    #[test]
+    fn umount_failure() {
+        let (config, sim) = Simulated::new();
+        let mut jail_config = jail_config(&config);
+        jail_config.filesystems = serde_json::from_str(
+            r#"[{"type": "nullfs", "source": "/data/www", "target": "/www", "options": ["ro"]},
+                {"type": "tmpfs", "target": "/tmp"}]"#,
+        ).unwrap();
+        let uuid = jail_config.uuid;
+        let root = {
+            let mut db = JDB::open(&config).unwrap();
+            db.insert(jail_config).unwrap().root
+        };
+        JDB::open(&config).unwrap().set_state(&uuid, JailState::Stopped).unwrap();
+        sim.add_dataset(root.as_str());
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        let tmp = format!("/{}/root/jail/tmp", root);
+        assert!(config.executor.run("umount", &[tmp.as_str()]).unwrap().success());
+        {
+            let db = JDB::open(&config).unwrap();
+            assert!(db.get(&uuid).unwrap().stop(&config, false).is_err());
+        }
+        // the other filesystems are still unmounted
+        assert!(sim.mounts().is_empty());
+        let db = JDB::open(&config).unwrap();
+        assert_eq!(JailState::Failed, db.get(&uuid).unwrap().idx.state);
+    }
+
+    #[test]
+    fn stop_timeout() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        {
+            let db = JDB::open(&config).unwrap();
+            db.get(&uuid).unwrap().start(&config).unwrap();
+        }
+        sim.hang_shutdown(uuid_str.as_str());
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(Shutdown::Timeout, jail.stop(&config, false).unwrap());
+        }
+        // the jail is torn down even though it did not shut down
+        assert_eq!(None, sim.jid(uuid_str.as_str()));
+        assert_eq!(0, sim.epair_count());
+        assert!(sim.mounts().is_empty());
+        assert!(sim.rctl_rules().is_empty());
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(JailState::Stopped, jail.idx.state);
+            jail.start(&config).unwrap();
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(Shutdown::Forced, jail.stop(&config, true).unwrap());
+        }
+        assert_eq!(None, sim.jid(uuid_str.as_str()));
+    }
+
+    #[test]
+    fn exec() {
+        let (mut config, _) = Simulated::new();
+        let uuid = provision(&config);
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!("/bin/csh", jail.shell(&config));
+            assert!(jail.exec(&config, None, &[], &["/bin/sh"]).is_err());
+            jail.start(&config).unwrap();
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            assert_eq!(0, jail.exec(&config, None, &[], &["/bin/ls", "-l"]).unwrap());
+            assert_eq!(
+                0,
+                jail.exec(&config, Some("root"), &["TERM=xterm"], &["/bin/ls"]).unwrap()
+            );
+            assert!(jail.exec(&config, None, &["TERM"], &["/bin/ls"]).is_err());
+            assert!(jail.exec(&config, None, &[], &[]).is_err());
+        }
+        config.settings.shells.insert(String::from("jail"), String::from("/bin/sh"));
+        {
+            let db = JDB::open(&config).unwrap();
+            assert_eq!("/bin/sh", db.get(&uuid).unwrap().shell(&config));
+        }
+    }
+
+    #[test]
+    fn info() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            sim.add_dataset(jail.idx.root.as_str());
+            let info = jail.info(&config).unwrap();
+            assert_eq!(None, info.outer_jid);
+            assert!(info.parameters.is_empty());
+            assert_eq!(5, info.rctl.len());
+            assert!(info.rctl.iter().all(|r| r.usage.is_none()));
+            assert!(info.mounts.is_empty());
+            jail.start(&config).unwrap();
+        }
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            let info = jail.info(&config).unwrap();
+            assert_eq!(JailState::Running, info.state);
+            assert_eq!(Some(1), info.outer_jid);
+            assert_eq!(Some(2), info.inner_jid);
+            assert_eq!(Some(&String::from("2")), info.parameters["inner"].get("jid"));
+            assert!(info.rctl.iter().all(|r| r.usage == Some(0)));
+            assert_eq!(1, info.nics.len());
+            assert_eq!("j1:net0", info.nics[0].host_interface);
+            assert!(info.nics[0].present);
+            assert_eq!(2, info.mounts.len());
+            assert_eq!(jail.idx.root, info.zfs.unwrap().dataset);
+            jail.stop(&config, true).unwrap();
+        }
+    }
+
+    #[test]
+    fn quota() {
+        let (config, sim) = Simulated::new();
+        let root = "zroot/jails/fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53";
+        sim.add_dataset(root);
+        sim.set_used(root, 2 * 1024 * 1024 * 1024);
+        set_quota(&config, root, 5).unwrap();
+        assert_eq!(
+            (5u64 * 1024 * 1024 * 1024).to_string(),
+            zfs::get_property(&config, root, "quota").unwrap()
+        );
+        assert!(set_quota(&config, root, 1).is_err());
+        assert_eq!(
+            (5u64 * 1024 * 1024 * 1024).to_string(),
+            zfs::get_property(&config, root, "quota").unwrap()
+        );
+        set_quota(&config, root, 0).unwrap();
+        assert_eq!("0", zfs::get_property(&config, root, "quota").unwrap());
+    }
+
+    #[test]
+    fn indestructible() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        let root = jail.idx.root.clone();
+        let snapshot = format!("{}@indestructible", root);
+        sim.add_dataset(root.as_str());
+        let mut new = jail.config.clone();
+        new.indestructible_zoneroot = Some(true);
+        // stopped jails are protected as well
+        let applied = jail.apply_update(&config, &new).unwrap();
+        assert_eq!(vec!["indestructible_zoneroot=true"], applied.live);
+        assert!(sim.has_dataset(snapshot.as_str()));
+        assert!(zfs::destroy(&config, snapshot.as_str()).is_err());
+        assert!(zfs::destroy(&config, root.as_str()).is_err());
+        // applying it again is a no-op
+        set_indestructible(&config, root.as_str(), true).unwrap();
+        jail.revert_update(&config, &new).unwrap();
+        assert!(!sim.has_dataset(snapshot.as_str()));
+        zfs::destroy(&config, root.as_str()).unwrap();
+    }
+
+    #[test]
+    fn apply_update() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            let mut new = jail.config.clone();
+            new.max_physical_memory = 2048;
+            // nothing to apply while the jail is stopped
+            assert!(jail.apply_update(&config, &new).unwrap().live.is_empty());
+            jail.start(&config).unwrap();
+        }
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        let mut new = jail.config.clone();
+        new.max_physical_memory = 2048;
+        new.max_lwps = 100;
+        new.hostname = String::from("changed");
+        let applied = jail.apply_update(&config, &new).unwrap();
+        assert_eq!(
+            vec!["memoryuse=2048M", "maxproc=100"],
+            applied.live
+        );
+        assert_eq!(vec!["hostname"], applied.restart);
+        let rules = sim.rctl_rules();
+        assert_eq!(5, rules.len());
+        assert!(rules.contains(&format!("jail:{}:memoryuse:deny=2048M", uuid_str)));
+        assert!(rules.contains(&format!("jail:{}:maxproc:deny=100", uuid_str)));
+        // memorylocked was filled in from max_physical_memory on create
+        assert!(rules.contains(&format!("jail:{}:memorylocked:deny=1024M", uuid_str)));
+        jail.revert_update(&config, &new).unwrap();
+        assert!(sim.rctl_rules().contains(&format!("jail:{}:memoryuse:deny=1024M", uuid_str)));
+        jail.stop(&config, true).unwrap();
+    }
+
+    #[test]
+    fn rctl_removed() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let uuid_str = uuid.hyphenated().to_string();
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        jail.start(&config).unwrap();
+        let rule = |resource: &str, amount: &str| format!("jail:{}:{}:deny={}", uuid_str, resource, amount);
+        let from = jail.config.rctl_limits();
+        let mut to: Vec<String> = from
+            .iter()
+            .filter(|r| !r.contains(":memorylocked:"))
+            .cloned()
+            .collect();
+        to.push(rule("openfiles", "1024"));
+        assert_eq!(
+            vec![
+                (None, Some(rule("openfiles", "1024"))),
+                (Some(rule("memorylocked", "1024M")), None),
+            ],
+            super::rctl_diff(&from, &to)
+        );
+        let mut applied = Applied::default();
+        let mut done = Vec::new();
+        jail.apply_rctl(&config, &from, &to, &mut applied, &mut done).unwrap();
+        assert_eq!(vec!["openfiles=1024", "memorylocked removed"], applied.live);
+        let rules = sim.rctl_rules();
+        assert!(rules.contains(&rule("openfiles", "1024")));
+        assert!(!rules.contains(&rule("memorylocked", "1024M")));
+        // undoing puts the removed rule back and drops the added one
+        for step in done.iter().rev() {
+            jail.undo(&config, step).unwrap();
+        }
+        let mut rules = sim.rctl_rules();
+        let mut expected: Vec<String> = from.into_iter().filter(|r| r != "-a").collect();
+        rules.sort();
+        expected.sort();
+        assert_eq!(expected, rules);
+        jail.stop(&config, true).unwrap();
+    }
+
+    #[test]
+    fn hotplug() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        {
+            let db = JDB::open(&config).unwrap();
+            let jail = db.get(&uuid).unwrap();
+            sim.add_dataset(jail.idx.root.as_str());
+            jail.start(&config).unwrap();
+        }
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        let mut nic = jail.config.nics[0].clone();
+        nic.interface = String::from("net1");
+        nic.mac = String::from("02:00:00:00:00:01");
+        nic.ip = String::from("10.0.0.2");
+        nic.legacy_to_ips();
+        nic.primary = false;
+
+        let mut added = jail.config.clone();
+        added.nics.push(nic.clone());
+        let applied = jail.apply_update(&config, &added).unwrap();
+        assert_eq!(vec!["add nic net1"], applied.live);
+        assert_eq!(2, sim.epair_count());
+        let script = sim.scripts().pop().unwrap();
+        assert!(script.contains("/sbin/ifconfig net1 inet 10.0.0.2/24; "));
+        assert!(!script.contains("192.168.1.2"));
+
+        jail.revert_update(&config, &added).unwrap();
+        assert_eq!(1, sim.epair_count());
+
+        let mut changed = jail.config.clone();
+        changed.nics[0].ip = String::from("192.168.1.3");
+        changed.nics[0].legacy_to_ips();
+        let applied = jail.apply_update(&config, &changed).unwrap();
+        assert_eq!(vec!["update nic net0"], applied.live);
+        let script = sim.scripts().pop().unwrap();
+        assert!(script.contains("/sbin/ifconfig net0 inet 192.168.1.2 -alias; "));
+        assert!(script.contains("/sbin/ifconfig net0 inet 192.168.1.3/24; "));
+        assert!(script.contains("/sbin/route add default -gateway 192.168.1.1; "));
+        changed.nics[0].vlan = Some(42);
+        let applied = jail.apply_update(&config, &changed).unwrap();
+        assert_eq!(vec!["update nic net0"], applied.restart);
+
+        let mut routed = jail.config.clone();
+        routed.routes.insert(String::from("10.0.0.0/8"), String::from("192.168.1.1"));
+        assert_eq!(
+            "/sbin/route -q add -net 10.0.0.0/8 192.168.1.1; ",
+            super::hotplug::route_changes(&jail.config, &routed)
+        );
+        assert_eq!(
+            "/sbin/route -q delete -net 10.0.0.0/8 192.168.1.1; ",
+            super::hotplug::route_changes(&routed, &jail.config)
+        );
+        routed.resolvers = vec![String::from("192.168.1.53")];
+        routed.maintain_resolvers = true;
+        let applied = jail.apply_update(&config, &routed).unwrap();
+        assert_eq!(vec!["routes", "resolvers"], applied.live);
+
+        // a NIC on an unknown network can not be added, the removal of
+        // net0 and the quota change are rolled back
+        let mut broken = jail.config.clone();
+        broken.quota = 10;
+        broken.nics = vec![nic.clone()];
+        broken.nics[0].nic_tag = String::from("missing");
+        assert!(jail.apply_update(&config, &broken).is_err());
+        assert_eq!(1, sim.epair_count());
+        assert_eq!(
+            (5u64 * 1024 * 1024 * 1024).to_string(),
+            zfs::get_property(&config, jail.idx.root.as_str(), "quota").unwrap()
+        );
+        jail.stop(&config, true).unwrap();
+    }
+
+    #[test]
+    fn plug_failure() {
+        let (config, sim) = Simulated::new();
+        let uuid = provision(&config);
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        jail.start(&config).unwrap();
+        let db = JDB::open(&config).unwrap();
+        let jail = db.get(&uuid).unwrap();
+        let mut nic = jail.config.nics[0].clone();
+        nic.interface = String::from("net1");
+        nic.mac = String::from("02:00:00:00:00:01");
+        sim.fail_bridge("bridge0");
+        assert!(jail.plug_nic(&config, &nic).is_err());
+        // the epair created for the NIC is gone again
+        assert_eq!(1, sim.epair_count());
+        jail.stop(&config, true).unwrap();
+    }
+}
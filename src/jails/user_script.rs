@@ -0,0 +1,158 @@
+//! Running the `user-script` from the customer metadata when a jail boots
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use config::Config;
+use errors::GenericError;
+use executor::Output;
+use jdb::{JDB, Lock, ScriptResult};
+
+use super::{Jail, JEXEC};
+
+/// Where the script is installed inside the inner jail, the same place
+/// SmartOS puts it
+static SCRIPT_PATH: &'static str = "/var/svc/mdata-user-script";
+static DAEMON: &'static str = "daemon";
+/// Seconds to wait for the database to record the result, a start of
+/// many jails can hold it for a while
+static LOCK_TIMEOUT: u64 = 300;
+/// Exit code recorded when the script could not be run at all
+static NOT_RUN: i32 = -1;
+
+/// Starts `vmadm user-script <uuid>` in the background so the script
+/// runs once the start is done and the lock released, like the
+/// `mdata:execute` service of SmartOS.
+pub fn start(config: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let exe = env::current_exe()?;
+    let exe = exe.to_string_lossy();
+    let uuid = uuid.hyphenated().to_string();
+    debug!("starting user-script"; "vm" => uuid.clone());
+    let output = config.executor.run(
+        DAEMON,
+        &["-f", exe.as_ref(), "user-script", uuid.as_str()],
+    )?;
+    if !output.success() {
+        return Err(GenericError::bx("could not start user-script"));
+    }
+    Ok(0)
+}
+
+/// Runs the `user-script` of a running jail if it is due and records
+/// the result in the index, failing to install it counts as a failed
+/// run.
+pub fn run(config: &Config, uuid: &Uuid) -> Result<Option<ScriptResult>, Box<Error>> {
+    let started = match begin(config, uuid)? {
+        Some(started) => started,
+        None => return Ok(None),
+    };
+    let exit_code = match JDB::open(config).and_then(|db| db.get(uuid)?.run_user_script(config)) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            crit!("could not run user-script"; "vm" => uuid.hyphenated().to_string(),
+                  "error" => e.to_string());
+            NOT_RUN
+        }
+    };
+    let result = started.finish(exit_code);
+    let _lock = Lock::acquire_within(config, Duration::from_secs(LOCK_TIMEOUT))?;
+    let mut db = JDB::open(config)?;
+    db.set_user_script(uuid, result.clone())?;
+    Ok(Some(result))
+}
+
+/// Checks that the script is due and records it as started while
+/// holding the lock, so a start during a long running script does not
+/// run it a second time.
+fn begin(config: &Config, uuid: &Uuid) -> Result<Option<ScriptResult>, Box<Error>> {
+    let _lock = Lock::acquire_within(config, Duration::from_secs(LOCK_TIMEOUT))?;
+    {
+        let db = JDB::open(config)?;
+        let jail = db.get(uuid)?;
+        if !jail.user_script_due() {
+            return Ok(None);
+        }
+        if jail.inner.is_none() {
+            return Err(GenericError::bx("VM is not running"));
+        }
+    }
+    let started = ScriptResult::started();
+    let mut db = JDB::open(config)?;
+    db.set_user_script(uuid, started.clone())?;
+    Ok(Some(started))
+}
+
+impl<'a> Jail<'a> {
+    /// If there is a `user-script` that has not run yet, or is to be run
+    /// on every boot and is not running right now
+    pub fn user_script_due(&self) -> bool {
+        if self.config.user_script().is_none() {
+            return false;
+        }
+        match self.idx.user_script {
+            None => true,
+            Some(ref result) => {
+                result.exit_code.is_some() && self.config.user_script_every_boot
+            }
+        }
+    }
+
+    /// Installs the `user-script` in the inner jail and runs it, the
+    /// output of both goes to `user-script.log` in the jails dataset.
+    fn run_user_script(&self, config: &Config) -> Result<i32, Box<Error>> {
+        let script = match self.config.user_script() {
+            Some(script) => script,
+            None => return Err(GenericError::bx("VM has no user-script")),
+        };
+        let inner = match self.inner {
+            Some(inner) => inner.id.to_string(),
+            None => return Err(GenericError::bx("VM is not running")),
+        };
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        let install = format!(
+            "mkdir -p /var/svc && cat > {0} && chmod 0700 {0}",
+            SCRIPT_PATH
+        );
+        let mut input = script.as_bytes();
+        let output = config.executor.pipe(
+            JEXEC,
+            &[inner.as_str(), "/bin/sh", "-c", install.as_str()],
+            &mut input,
+        )?;
+        if output.success() {
+            debug!("running user-script"; "vm" => uuid.clone());
+            let output = config.executor.run(JEXEC, &[inner.as_str(), SCRIPT_PATH])?;
+            return Ok(self.script_exit_code(&output));
+        }
+        crit!("could not install user-script"; "vm" => uuid.clone(),
+              "stderr" => output.stderr.clone());
+        Ok(self.script_exit_code(&output))
+    }
+
+    /// Logs the output of the script and returns its exit code
+    fn script_exit_code(&self, output: &Output) -> i32 {
+        let uuid = self.idx.uuid.hyphenated().to_string();
+        if !output.success() {
+            warn!("user-script failed"; "vm" => uuid.clone(), "exit_code" => output.code);
+        }
+        if let Err(e) = self.write_user_script_log(output) {
+            warn!("could not write user-script log"; "vm" => uuid, "error" => e.to_string());
+        }
+        output.code
+    }
+
+    /// Writes the output of the script next to the jail root on the host
+    /// so the jail can not tamper with it
+    fn write_user_script_log(&self, output: &Output) -> Result<i32, Box<Error>> {
+        let path = format!("/{}/user-script.log", self.idx.root);
+        let mut file = File::create(path.as_str())?;
+        file.write_all(output.stdout.as_bytes())?;
+        file.write_all(output.stderr.as_bytes())?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,252 @@
+//! Migrations for the jail database layout and index format
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+use config::Config;
+use errors::GenericError;
+use jails::{self, JailOSEntry};
+
+/// Current version of the index format
+pub const VERSION: u32 = 1;
+
+/// Where vmadm 0.1.0 kept the jail database
+static LEGACY_CONF_DIR: &'static str = "/etc/jails";
+
+/// Jails running on the host by name, as returned by `jails::list`
+pub type Running = HashMap<String, JailOSEntry>;
+
+/// A migration takes the index of version `n` to `n + 1` and records
+/// what it changed.
+type Migration = fn(&mut Value, &Running, &mut Vec<String>) -> Result<(), Box<Error>>;
+
+/// Migrations indexed by the version they migrate from
+static MIGRATIONS: &'static [Migration] = &[v0_to_v1];
+
+/// Version 0 wrote entries with a state that never changed and, before
+/// 0.2.0, without a jail_type. The state is taken from the running jails.
+fn v0_to_v1(index: &mut Value, running: &Running, changes: &mut Vec<String>) -> Result<(), Box<Error>> {
+    let entries = index
+        .get_mut("entries")
+        .and_then(|e| e.as_array_mut())
+        .ok_or_else(|| GenericError::bx("index has no entries"))?;
+    for entry in entries.iter_mut() {
+        let entry = entry.as_object_mut().ok_or_else(
+            || GenericError::bx("index entry is not an object"),
+        )?;
+        let uuid = entry
+            .get("uuid")
+            .and_then(|u| u.as_str())
+            .unwrap_or("<unknown>")
+            .to_string();
+        if !entry.contains_key("jail_type") {
+            changes.push(format!("{}: add jail_type \"base\"", uuid));
+            entry.insert(String::from("jail_type"), Value::from("base"));
+        }
+        let state = if running.contains_key(&uuid) {
+            "running"
+        } else {
+            "stopped"
+        };
+        if entry.get("state").and_then(|s| s.as_str()) != Some(state) {
+            changes.push(format!("{}: set state \"{}\"", uuid, state));
+            entry.insert(String::from("state"), Value::from(state));
+        }
+        entry.insert(String::from("version"), Value::from(1));
+    }
+    Ok(())
+}
+
+fn version(value: &Value) -> u32 {
+    value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32
+}
+
+/// Brings a raw index up to the current version, returns the version
+/// it started at and a list of the changes made.
+pub fn index(mut value: Value, running: &Running) -> Result<(Value, u32, Vec<String>), Box<Error>> {
+    let from = version(&value);
+    if from > VERSION {
+        return Err(GenericError::bx(
+            format!(
+                "index version {} is newer than the supported version {}",
+                from,
+                VERSION
+            ).as_str(),
+        ));
+    }
+    let mut changes = Vec::new();
+    for v in from..VERSION {
+        debug!("Migrating index"; "from" => v, "to" => v + 1);
+        changes.push(format!("index: version {} -> {}", v, v + 1));
+        MIGRATIONS[v as usize](&mut value, running, &mut changes)?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert(String::from("version"), Value::from(v + 1));
+        }
+    }
+    Ok((value, from, changes))
+}
+
+/// Path of the backup kept for an index of the given version
+pub fn backup_path(config: &Config, version: u32) -> PathBuf {
+    let mut path = PathBuf::from(config.settings.conf_dir.as_str());
+    path.push(format!("index.v{}.bak", version));
+    path
+}
+
+/// Files of a 0.1.0 database that need to be copied to the config
+/// directory, empty if there is nothing to migrate.
+fn legacy_files(config: &Config) -> Result<Vec<(PathBuf, PathBuf)>, Box<Error>> {
+    let mut index = PathBuf::from(config.settings.conf_dir.as_str());
+    index.push("index");
+    let legacy = Path::new(LEGACY_CONF_DIR);
+    if index.exists() || legacy == Path::new(config.settings.conf_dir.as_str()) ||
+        !legacy.join("index").exists()
+    {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(legacy)? {
+        let path = entry?.path();
+        let name = match path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => continue,
+        };
+        let is_db_file = name == Path::new("index") ||
+            path.extension().map(|e| e == "json").unwrap_or(false);
+        if path.is_file() && is_db_file {
+            files.push((path.clone(), Path::new(config.settings.conf_dir.as_str()).join(name)));
+        }
+    }
+    Ok(files)
+}
+
+/// If there is a 0.1.0 database that has not been copied to the config
+/// directory yet
+pub fn legacy_pending(config: &Config) -> Result<bool, Box<Error>> {
+    Ok(!legacy_files(config)?.is_empty())
+}
+
+/// Copies a 0.1.0 database into the config directory, the old
+/// directory is left in place as a backup.
+pub fn legacy_dir(config: &Config, dry_run: bool) -> Result<Vec<String>, Box<Error>> {
+    let files = legacy_files(config)?;
+    let mut changes = Vec::new();
+    if files.is_empty() {
+        return Ok(changes);
+    }
+    if !dry_run {
+        warn!("Migrating legacy database"; "from" => LEGACY_CONF_DIR,
+              "to" => config.settings.conf_dir.clone());
+        fs::create_dir_all(config.settings.conf_dir.as_str())?;
+    }
+    for (from, to) in files {
+        changes.push(format!("copy {} -> {}", from.display(), to.display()));
+        if !dry_run {
+            fs::copy(&from, &to)?;
+        }
+    }
+    Ok(changes)
+}
+
+/// Migrates the database directory and index, with `dry_run` only the
+/// list of changes is returned.
+pub fn run(config: &Config, dry_run: bool) -> Result<Vec<String>, Box<Error>> {
+    let legacy = legacy_files(config)?;
+    let mut changes = legacy_dir(config, dry_run)?;
+    let mut path = PathBuf::from(config.settings.conf_dir.as_str());
+    path.push("index");
+    // on a dry run the legacy index has not been copied yet
+    let source = if dry_run && !legacy.is_empty() {
+        Path::new(LEGACY_CONF_DIR).join("index")
+    } else {
+        path.clone()
+    };
+    if !source.exists() {
+        return Ok(changes);
+    }
+    let raw: Value = serde_json::from_reader(fs::File::open(&source)?)?;
+    let (value, from, mut index_changes) = index(raw, &jails::list(config)?)?;
+    if index_changes.is_empty() {
+        return Ok(changes);
+    }
+    let backup = backup_path(config, from);
+    index_changes.push(format!("backup {} -> {}", path.display(), backup.display()));
+    changes.append(&mut index_changes);
+    if !dry_run {
+        fs::copy(&path, &backup)?;
+        super::write_atomic(&path, &value)?;
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use jdb::migrate::*;
+
+    fn running(uuid: &str) -> Running {
+        let mut running = Running::new();
+        running.insert(
+            String::from(uuid),
+            JailOSEntry {
+                uuid: String::from(uuid),
+                id: 1,
+            },
+        );
+        running
+    }
+
+    fn raw(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn v0() {
+        let raw = raw(r#"{
+            "version": 0,
+            "entries": [
+                {"version": 0, "uuid": "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53",
+                 "root": "zroot/jails/fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53"}
+            ]
+        }"#);
+        let (value, from, changes) = index(raw, &Running::new()).unwrap();
+        assert_eq!(0, from);
+        assert_eq!(3, changes.len());
+        assert_eq!(Value::from(1), value["version"]);
+        assert_eq!(Value::from(1), value["entries"][0]["version"]);
+        assert_eq!(Value::from("stopped"), value["entries"][0]["state"]);
+        assert_eq!(Value::from("base"), value["entries"][0]["jail_type"]);
+    }
+
+    #[test]
+    fn v0_running() {
+        let uuid = "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53";
+        let raw = raw(r#"{
+            "version": 0,
+            "entries": [
+                {"version": 0, "uuid": "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53",
+                 "root": "zroot/jails/fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53",
+                 "state": "stopped", "jail_type": "base"}
+            ]
+        }"#);
+        let (value, _, changes) = index(raw, &running(uuid)).unwrap();
+        assert_eq!(2, changes.len());
+        assert_eq!(Value::from("running"), value["entries"][0]["state"]);
+    }
+
+    #[test]
+    fn current() {
+        let raw = raw(format!(r#"{{"version": {}, "entries": []}}"#, VERSION).as_str());
+        let (_, _, changes) = index(raw, &Running::new()).unwrap();
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn too_new() {
+        let raw = raw(format!(r#"{{"version": {}, "entries": []}}"#, VERSION + 1).as_str());
+        assert!(index(raw, &Running::new()).is_err());
+    }
+}
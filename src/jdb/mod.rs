@@ -2,26 +2,100 @@
 
 use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str;
-use std::cmp::PartialEq;
+use std::cmp::{Ordering, PartialEq};
+use std::cell::Cell as StdCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::slice::Iter;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use prettytable::Table;
 use prettytable::format;
 use prettytable::row::Row;
 use prettytable::cell::Cell;
 use uuid::Uuid;
-use serde_json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{self, Value};
+use tempfile::NamedTempFile;
+use libc;
 
 use jails::Jail;
 use jails;
 use jail_config::JailConfig;
 
-use errors::{NotFoundError, ConflictError, GenericError};
+use errors::{NotFoundError, AmbiguousError, ConflictError, GenericError, LockedError};
 use config::Config;
+use filter::{self, Filter};
+
+pub mod migrate;
+
+/// Lifecycle state of a jail as recorded in the index
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JailState {
+    /// The jail is being created
+    Provisioning,
+    /// The jail is created but not running
+    Stopped,
+    /// The jail is being started
+    Starting,
+    /// The jail is running
+    Running,
+    /// The jail is being stopped
+    Stopping,
+    /// A lifecycle operation failed half way
+    Failed,
+    /// The jail is being deleted
+    Deleting,
+}
+
+impl JailState {
+    /// Checks if a jail may move from this state to `to`
+    pub fn can_transition(&self, to: JailState) -> bool {
+        use self::JailState::*;
+        matches!(
+            (*self, to),
+            (Provisioning, Stopped) |
+            (Provisioning, Failed) |
+            (Provisioning, Deleting) |
+            (Stopped, Starting) |
+            (Stopped, Deleting) |
+            (Starting, Running) |
+            (Starting, Failed) |
+            (Running, Stopping) |
+            (Running, Failed) |
+            (Stopping, Stopped) |
+            (Stopping, Failed) |
+            (Failed, Starting) |
+            (Failed, Stopping) |
+            (Failed, Deleting) |
+            (Deleting, Failed)
+        )
+    }
+}
+
+impl fmt::Display for JailState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            JailState::Provisioning => "provisioning",
+            JailState::Stopped => "stopped",
+            JailState::Starting => "starting",
+            JailState::Running => "running",
+            JailState::Stopping => "stopping",
+            JailState::Failed => "failed",
+            JailState::Deleting => "deleting",
+        };
+        write!(f, "{}", s)
+    }
+}
 
 /// `JailDB` index entry
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,8 +105,44 @@ pub struct IdxEntry {
     pub uuid: Uuid,
     /// ZFS dataset root
     pub root: String,
-    state: String,
+    /// Recorded lifecycle state
+    pub state: JailState,
     jail_type: String,
+    /// Result of the last `user-script` run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_script: Option<ScriptResult>,
+}
+
+/// Outcome of running a jails `user-script`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScriptResult {
+    /// exit code of the script, -1 if it was killed by a signal and
+    /// empty while it is still running
+    pub exit_code: Option<i32>,
+    /// when the script was started
+    pub started: DateTime<Utc>,
+    /// when the script finished
+    pub finished: Option<DateTime<Utc>>,
+}
+
+impl ScriptResult {
+    /// A run of the script that was just started
+    pub fn started() -> Self {
+        ScriptResult {
+            exit_code: None,
+            started: Utc::now(),
+            finished: None,
+        }
+    }
+
+    /// The run finished with `exit_code`
+    pub fn finish(self, exit_code: i32) -> Self {
+        ScriptResult {
+            exit_code: Some(exit_code),
+            started: self.started,
+            finished: Some(Utc::now()),
+        }
+    }
 }
 
 
@@ -49,12 +159,103 @@ struct Index {
     pub entries: Vec<IdxEntry>,
 }
 
+/// Milliseconds between attempts to take a busy lock
+static LOCK_RETRY_MS: u64 = 100;
+
+/// Advisory lock on the jail database, mutating commands hold it for
+/// their whole run so concurrent vmadm invocations can't clobber each
+/// other. The lock is released when dropped or when the process dies.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Acquires the lock on the config directory, fails right away if
+    /// another process is holding it.
+    pub fn acquire(config: &Config) -> Result<Self, Box<Error>> {
+        // the config directory does not exist yet before a legacy
+        // database was migrated
+        fs::create_dir_all(config.settings.conf_dir.as_str())?;
+        let mut path = PathBuf::from(config.settings.conf_dir.as_str());
+        path.push("index.lock");
+        debug!("Locking jdb"; "lock" => path.to_string_lossy().as_ref());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                warn!("Database is locked"; "lock" => path.to_string_lossy().as_ref(),
+                      "holder" => holder.trim());
+                return Err(LockedError::bx(path.to_string_lossy().as_ref(), holder.trim()));
+            }
+            return Err(Box::new(error));
+        }
+        // record who holds the lock so a blocked process can tell
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(Lock { file: file })
+    }
+
+    /// Acquires the lock like `acquire` but waits up to `timeout` for
+    /// another process to release it.
+    pub fn acquire_within(config: &Config, timeout: Duration) -> Result<Self, Box<Error>> {
+        let start = Instant::now();
+        loop {
+            match Lock::acquire(config) {
+                Err(ref e) if e.downcast_ref::<LockedError>().is_some() && start.elapsed() < timeout => {
+                    thread::sleep(Duration::from_millis(LOCK_RETRY_MS))
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+/// Writes a value as JSON to a temporary file next to `path` and
+/// renames it in place so readers never see a partially written file.
+fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<Error>> {
+    let dir = path.parent().ok_or_else(
+        || GenericError::bx("could not determine config directory"),
+    )?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(tmp.as_mut(), value)?;
+    tmp.sync_all()?;
+    tmp.persist(path)?;
+    // make sure the rename itself hits the disk
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Columns `list` prints when none are given
+pub static DEFAULT_COLUMNS: &'static [&'static str] = &["uuid", "type", "ram", "state", "jls", "alias"];
+
 /// `JailDB` main struct
 #[derive(Debug)]
 pub struct JDB<'a> {
     config: &'a Config,
     index: Index,
     jails: HashMap<String, jails::JailOSEntry>,
+    /// version the index was migrated from, a backup of the old index
+    /// is written before it gets overwritten the first time
+    migrated_from: StdCell<Option<u32>>,
 }
 
 impl<'a> JDB<'a> {
@@ -62,7 +263,7 @@ impl<'a> JDB<'a> {
     ///„ # Arguments
     ///
     /// * `path` - Path of the **index file**, the locatio of the
-    ///            file is also where the seperate configs live.
+    ///   file is also where the seperate configs live.
     ///
     /// # Example
     ///
@@ -71,32 +272,51 @@ impl<'a> JDB<'a> {
     /// use jdb::JDB;
     /// let db = JDB::open("/usr/local/etc/vmadm/index");
     /// ```
-
     pub fn open(config: &'a Config) -> Result<Self, Box<Error>> {
+        // copying the legacy database needs the lock, which readers
+        // do not hold, so it is left to `db migrate` and startup
+        if migrate::legacy_pending(config)? {
+            warn!("Found legacy database, run `vmadm db migrate` to move it";
+                  "conf_dir" => config.settings.conf_dir.clone());
+            return Err(GenericError::bx(
+                "Found a vmadm 0.1.0 database, run `vmadm db migrate` first",
+            ));
+        }
         let mut idx_file = PathBuf::from(config.settings.conf_dir.as_str());
         idx_file.push("index");
         debug!("Opening jdb"; "index" => idx_file.to_string_lossy().as_ref());
         match File::open(idx_file) {
             Ok(file) => {
-                let index: Index = serde_json::from_reader(file)?;
+                let raw: serde_json::Value = serde_json::from_reader(file)?;
+                let running = jails::list(config)?;
+                let (raw, from, changes) = migrate::index(raw, &running)?;
+                let migrated_from = if changes.is_empty() {
+                    None
+                } else {
+                    info!("Migrated index from version {}", from; "changes" => changes.join(", "));
+                    Some(from)
+                };
+                let index: Index = serde_json::from_value(raw)?;
                 debug!("Found {} entries", index.entries.len());
                 Ok(JDB {
                     index: index,
                     config: config,
-                    jails: jails::list()?,
+                    jails: running,
+                    migrated_from: StdCell::new(migrated_from),
                 })
             }
             Err(_) => {
                 warn!("No database found creating new one.");
                 let entries: Vec<IdxEntry> = Vec::new();
                 let index: Index = Index {
-                    version: 0,
+                    version: migrate::VERSION,
                     entries: entries,
                 };
                 let db = JDB {
                     index: index,
                     config: config,
-                    jails: jails::list()?,
+                    jails: jails::list(config)?,
+                    migrated_from: StdCell::new(None),
                 };
                 db.save()?;
                 Ok(db)
@@ -112,30 +332,30 @@ impl<'a> JDB<'a> {
         debug!("Inserting new vm"; "vm" => &config.uuid.hyphenated().to_string());
         match self.find(&config.uuid) {
             None => {
-                let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
-                path.push(config.uuid.hyphenated().to_string());
-                path.set_extension("json");
-                let file = File::create(path)?;
+                let path = self.config_path(&config.uuid);
+                // write the config before the index references it
+                write_atomic(&path, &config)?;
                 let mut root = String::from(self.config.settings.pool.as_str());
                 root.push('/');
                 root.push_str(&config.uuid.hyphenated().to_string());
                 let e = IdxEntry {
-                    version: 0,
-                    uuid: config.uuid.clone(),
-                    state: String::from("stopped"),
+                    version: migrate::VERSION,
+                    uuid: config.uuid,
+                    state: JailState::Provisioning,
                     jail_type: String::from("base"),
                     root: root.clone(),
+                    user_script: None,
                 };
                 self.index.entries.push(e);
                 self.save()?;
-                serde_json::to_writer(file, &config)?;
                 // This is ugly but I don't know any better.
                 Ok(IdxEntry {
-                    version: 0,
-                    uuid: config.uuid.clone(),
-                    state: String::from("stopped"),
+                    version: migrate::VERSION,
+                    uuid: config.uuid,
+                    state: JailState::Provisioning,
                     jail_type: String::from("base"),
                     root: root.clone(),
+                    user_script: None,
                 })
             }
             Some(_) => {
@@ -156,18 +376,66 @@ impl<'a> JDB<'a> {
             }
 
             Some(_) => {
-                let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
-                path.push(config.uuid.hyphenated().to_string());
-                path.set_extension("json");
+                let path = self.config_path(&config.uuid);
                 debug!("Updating config file"; "file" => path.to_str(), "vm" => &config.uuid.hyphenated().to_string());
-                let file = File::create(path)?;
-                serde_json::to_writer(file, &config)?;
+                write_atomic(&path, &config)?;
                 // This is ugly but I don't know any better.
                 Ok(0)
             }
         }
     }
 
+    /// Moves a jail to a new lifecycle state and saves the index,
+    /// returns the previous state. Invalid transitions are refused.
+    pub fn set_state(&mut self, uuid: &Uuid, state: JailState) -> Result<JailState, Box<Error>> {
+        let index = match self.index.entries.iter().position(|x| x.uuid == *uuid) {
+            Some(index) => index,
+            None => return Err(NotFoundError::bx(uuid)),
+        };
+        let old = self.index.entries[index].state;
+        if !old.can_transition(state) {
+            warn!("Refusing state transition"; "vm" => uuid.hyphenated().to_string(),
+                  "from" => old.to_string(), "to" => state.to_string());
+            return Err(GenericError::bx(
+                format!("Jail {} can not go from {} to {}", uuid, old, state).as_str(),
+            ));
+        }
+        debug!("Changing state"; "vm" => uuid.hyphenated().to_string(),
+               "from" => old.to_string(), "to" => state.to_string());
+        self.index.entries[index].state = state;
+        self.save()?;
+        Ok(old)
+    }
+
+    /// Records the state a jail was found in without checking the
+    /// transition, for when the index disagrees with the OS or a stop is
+    /// forced. Returns the previous state.
+    pub fn force_state(&mut self, uuid: &Uuid, state: JailState) -> Result<JailState, Box<Error>> {
+        let index = match self.index.entries.iter().position(|x| x.uuid == *uuid) {
+            Some(index) => index,
+            None => return Err(NotFoundError::bx(uuid)),
+        };
+        let old = self.index.entries[index].state;
+        warn!("Forcing state"; "vm" => uuid.hyphenated().to_string(),
+              "from" => old.to_string(), "to" => state.to_string());
+        self.index.entries[index].state = state;
+        self.save()?;
+        Ok(old)
+    }
+
+    /// Records the result of a `user-script` run and saves the index
+    pub fn set_user_script(&mut self, uuid: &Uuid, result: ScriptResult) -> Result<i32, Box<Error>> {
+        let index = match self.index.entries.iter().position(|x| x.uuid == *uuid) {
+            Some(index) => index,
+            None => return Err(NotFoundError::bx(uuid)),
+        };
+        debug!("Recording user-script result"; "vm" => uuid.hyphenated().to_string(),
+               "exit_code" => result.exit_code);
+        self.index.entries[index].user_script = Some(result);
+        self.save()?;
+        Ok(0)
+    }
+
     /// Removes a jail with a given uuid from the index and removes it's
     /// config file.
     pub fn remove(self: &'a mut JDB<'a>, uuid: &Uuid) -> Result<usize, Box<Error>> {
@@ -175,41 +443,51 @@ impl<'a> JDB<'a> {
         match self.find(uuid) {
             None => Err(NotFoundError::bx(uuid)),
             Some(index) => {
-                // remove the config file first
-                let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
-                path.push(uuid.hyphenated().to_string());
-                path.set_extension("json");
-                fs::remove_file(&path)?;
+                // drop the index entry first so a crash leaves at most
+                // an orphaned config file behind
                 self.index.entries.remove(index);
                 self.save()?;
+                let path = self.config_path(uuid);
+                fs::remove_file(&path)?;
                 Ok(index)
             }
         }
     }
 
+    /// Path of the config file of a jail
+    pub fn config_path(&self, uuid: &Uuid) -> PathBuf {
+        let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
+        path.push(uuid.hyphenated().to_string());
+        path.set_extension("json");
+        path
+    }
+
     /// Reads the config file for a given entry
     fn config(self: &'a JDB<'a>, entry: &IdxEntry) -> Result<JailConfig, Box<Error>> {
         debug!("Loading vm config"; "vm" => &entry.uuid.hyphenated().to_string());
-        let mut config_path = PathBuf::from(self.config.settings.conf_dir.as_str());
-        config_path.push(entry.uuid.hyphenated().to_string());
-        config_path.set_extension("json");
+        let config_path = self.config_path(&entry.uuid);
         match config_path.to_str() {
             Some(path) => JailConfig::from_file(self.config, path),
             None => Err(GenericError::bx("could not generate vm config path")),
         }
     }
     /// Saves the database
-    fn save(self: &'a JDB<'a>) -> Result<usize, Box<Error>> {
+    fn save(&self) -> Result<usize, Box<Error>> {
         debug!("Saving database");
         let mut path = PathBuf::from(self.config.settings.conf_dir.as_str());
         path.push("index");
-        let file = File::create(path)?;
-        serde_json::to_writer(file, &self.index)?;
+        if let Some(from) = self.migrated_from.get() {
+            let backup = migrate::backup_path(self.config, from);
+            info!("Backing up index before migration"; "backup" => backup.to_string_lossy().as_ref());
+            fs::copy(&path, &backup)?;
+            self.migrated_from.set(None);
+        }
+        write_atomic(&path, &self.index)?;
         Ok(self.index.entries.len())
     }
 
     /// Fetches a `Jail` from the `JDB`.
-    pub fn get(self: &'a JDB<'a>, uuid: &Uuid) -> Result<Jail, Box<Error>> {
+    pub fn get(self: &'a JDB<'a>, uuid: &Uuid) -> Result<Jail<'a>, Box<Error>> {
         match self.find(uuid) {
             None => Err(NotFoundError::bx(uuid)),
             Some(index) => {
@@ -231,6 +509,17 @@ impl<'a> JDB<'a> {
         }
     }
 
+    /// Resolves a full UUID, a unique UUID prefix or a unique alias to
+    /// the UUID of a jail.
+    pub fn resolve(self: &'a JDB<'a>, id: &str) -> Result<Uuid, Box<Error>> {
+        let mut candidates = Vec::new();
+        for entry in self.iter() {
+            let conf = self.config(entry)?;
+            candidates.push((entry.uuid, conf.alias));
+        }
+        resolve_id("jail", id, &candidates)
+    }
+
     /// Finds an entry for a given uuid
     fn find(self: &'a JDB<'a>, uuid: &Uuid) -> Option<usize> {
         self.index.entries.iter().position(|x| x.uuid == *uuid)
@@ -241,19 +530,61 @@ impl<'a> JDB<'a> {
         self.index.entries.iter()
     }
 
-    /// Prints the jdb database
-    pub fn print(self: &'a JDB<'a>, headerless: bool, parsable: bool) -> Result<i32, Box<Error>> {
+    /// Prints the jdb database with the given columns, sorted by the
+    /// `sort` fields (descending when prefixed with `-`) and limited to
+    /// the jails matching all `filters`.
+    pub fn print(
+        self: &'a JDB<'a>,
+        headerless: bool,
+        parsable: bool,
+        fields: &[&str],
+        sort: &[&str],
+        filters: &[Filter],
+    ) -> Result<i32, Box<Error>> {
+        // derived fields can be costly so they are only added when used
+        let mut wanted: Vec<&str> = fields.to_vec();
+        wanted.extend(sort.iter().map(|s| sort_key(s).0));
+        wanted.extend(filters.iter().map(|f| f.field.as_str()));
+
+        let mut rows = Vec::new();
+        for e in self.iter() {
+            let obj = self.list_object(e, &wanted)?;
+            if filters.iter().all(|f| f.matches(&obj)) {
+                rows.push(obj);
+            }
+        }
+        rows.sort_by(|a, b| {
+            for key in sort {
+                let (field, descending) = sort_key(key);
+                let ord = compare(&column(a, field), &column(b, field));
+                if ord != Ordering::Equal {
+                    return if descending { ord.reverse() } else { ord };
+                }
+            }
+            Ordering::Equal
+        });
+
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_CLEAN);
         if !headerless {
+            let header: Vec<String> = fields.iter().map(|f| f.to_uppercase()).collect();
             if parsable {
-                println!("{}:{}:{}:{}:{}", "UUID", "TYPE", "RAM", "STATE", "ALIAS");
+                println!("{}", header.join(":"));
             } else {
-                table.add_row(row!["UUID", "TYPE", "RAM", "STATE", "ALIAS"]);
+                table.add_row(Row::new(
+                    header.iter().map(|h| Cell::new(h.as_str())).collect(),
+                ));
             }
         }
-        for e in self.iter() {
-            self.print_entry(e, &mut table, parsable)?;
+        for obj in rows.iter() {
+            let values: Vec<String> = fields.iter().map(|f| column(obj, f)).collect();
+            if parsable {
+                println!("{}", values.join(":"));
+            } else {
+                table.add_row(Row::new(
+                    values.iter().map(|v| Cell::new(v.as_str())).collect(),
+                ));
+            }
         }
         if !parsable {
             table.printstd()
@@ -261,40 +592,163 @@ impl<'a> JDB<'a> {
         Ok(0)
     }
 
-    /// Gets the config and prints an etry
-    fn print_entry(
-        self: &'a JDB<'a>,
-        entry: &IdxEntry,
-        table: &mut Table,
-        parsable: bool,
-    ) -> Result<i32, Box<Error>> {
-        let conf = self.config(entry)?;
-        let id = match self.jails.get(&conf.uuid.hyphenated().to_string()) {
-            Some(jail) => jail.id,
-            _ => 0,
-        };
-        let state = match id {
-            0 => &entry.state,
-            _ => "running",
-        };
-        if parsable {
-            println!(
-                "{}:{}:{}:{}:{}",
-                conf.uuid,
-                "OS",
-                conf.max_physical_memory,
-                state,
-                conf.alias
-            );
+    /// Builds the object a list row is made from, the jails JSON plus
+    /// the derived fields in `wanted`.
+    fn list_object(self: &'a JDB<'a>, entry: &IdxEntry, wanted: &[&str]) -> Result<Value, Box<Error>> {
+        let jail = self.get(&entry.uuid)?;
+        let mut obj = jail.json()?;
+        if let Some(map) = obj.as_object_mut() {
+            for field in wanted {
+                let key = field.split('.').next().unwrap_or(field);
+                if map.contains_key(key) {
+                    continue;
+                }
+                if let Some(value) = jail.derived(self.config, key) {
+                    map.insert(String::from(key), value);
+                }
+            }
+        }
+        Ok(obj)
+    }
+}
+
+/// Picks the one candidate that `id` refers to, either by its full
+/// UUID, a unique prefix of it or by a unique name.
+pub fn resolve_id(what: &str, id: &str, candidates: &[(Uuid, String)]) -> Result<Uuid, Box<Error>> {
+    if let Ok(uuid) = Uuid::parse_str(id) {
+        return if candidates.iter().any(|&(u, _)| u == uuid) {
+            Ok(uuid)
         } else {
-            table.add_row(Row::new(vec![
-                Cell::new(conf.uuid.hyphenated().to_string().as_str()),
-                Cell::new("OS"),
-                Cell::new(conf.max_physical_memory.to_string().as_str()),
-                Cell::new(state),
-                Cell::new(conf.alias.as_str()),
-            ]));
+            Err(NotFoundError::bx_id(what, id))
         };
-        Ok(0)
+    }
+    if id.is_empty() {
+        return Err(NotFoundError::bx_id(what, id));
+    }
+    let prefix = id.to_lowercase();
+    let mut found: Vec<Uuid> = Vec::new();
+    for &(uuid, ref name) in candidates {
+        let matches = uuid.hyphenated().to_string().starts_with(prefix.as_str()) || name == id;
+        if matches && !found.contains(&uuid) {
+            found.push(uuid);
+        }
+    }
+    match found.len() {
+        0 => Err(NotFoundError::bx_id(what, id)),
+        1 => Ok(found[0]),
+        _ => {
+            let matches = found.iter().map(|u| u.hyphenated().to_string()).collect();
+            Err(AmbiguousError::bx(what, id, matches))
+        }
+    }
+}
+
+/// Splits a sort key into the field and if it is descending
+fn sort_key(key: &str) -> (&str, bool) {
+    match key.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (key, false),
+    }
+}
+
+/// Compares two column values, numerically when both are numbers
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// String value of a column for an object, `-` when there is none
+fn column(obj: &Value, field: &str) -> String {
+    let values: Vec<String> = filter::lookup(obj, field)
+        .iter()
+        .flat_map(|v| match **v {
+            Value::Array(ref a) => a.iter().collect(),
+            ref v => vec![v],
+        })
+        .filter(|v| !v.is_null())
+        .filter_map(filter::to_string)
+        .collect();
+    if values.is_empty() {
+        String::from("-")
+    } else {
+        values.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Simulated;
+    use std::cmp::Ordering;
+    use serde_json;
+    use uuid::Uuid;
+    use std::time::{Duration, Instant};
+    use jdb::{Lock, JailState, column, compare, sort_key, resolve_id};
+
+    #[test]
+    fn transitions() {
+        assert!(JailState::Stopped.can_transition(JailState::Starting));
+        assert!(JailState::Running.can_transition(JailState::Stopping));
+        assert!(JailState::Failed.can_transition(JailState::Starting));
+        assert!(!JailState::Running.can_transition(JailState::Starting));
+        assert!(!JailState::Stopped.can_transition(JailState::Running));
+        assert!(!JailState::Running.can_transition(JailState::Deleting));
+    }
+
+    #[test]
+    fn columns() {
+        let obj = serde_json::from_str(
+            r#"{"alias": "a", "jid": null, "nics": [{"ip": "10.0.0.1"}, {"ip": "10.0.0.2"}],
+                "tags": {"role": "db", "shard": 3}}"#,
+        ).unwrap();
+        assert_eq!("a", column(&obj, "alias"));
+        assert_eq!("-", column(&obj, "jid"));
+        assert_eq!("-", column(&obj, "missing"));
+        assert_eq!("10.0.0.1,10.0.0.2", column(&obj, "nics.*.ip"));
+        assert_eq!("3", column(&obj, "tags.shard"));
+    }
+
+    #[test]
+    fn sorting() {
+        assert_eq!(("ram", true), sort_key("-ram"));
+        assert_eq!(("ram", false), sort_key("ram"));
+        assert_eq!(Ordering::Less, compare("512", "1024"));
+        assert_eq!(Ordering::Greater, compare("b", "a"));
+    }
+
+    #[test]
+    fn resolve() {
+        let a = Uuid::parse_str("fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53").unwrap();
+        let b = Uuid::parse_str("fe1b9b05-1f3e-4b11-b0ae-8494bb6ecd53").unwrap();
+        let candidates = vec![(a, String::from("web01")), (b, String::from("db01"))];
+        assert_eq!(a, resolve_id("jail", "fe0b9b05-1f3e-4b11-b0ae-8494bb6ecd53", &candidates).unwrap());
+        assert_eq!(a, resolve_id("jail", "fe0", &candidates).unwrap());
+        assert_eq!(a, resolve_id("jail", "FE0B", &candidates).unwrap());
+        assert_eq!(b, resolve_id("jail", "db01", &candidates).unwrap());
+        let ambiguous = resolve_id("jail", "fe", &candidates).unwrap_err();
+        assert!(ambiguous.to_string().starts_with("Ambiguous jail fe"));
+        let missing = resolve_id("jail", "web02", &candidates).unwrap_err();
+        assert_eq!("jail not found: web02", missing.to_string());
+        assert!(resolve_id("jail", "00000000-1f3e-4b11-b0ae-8494bb6ecd53", &candidates).is_err());
+        assert!(resolve_id("jail", "", &candidates).is_err());
+    }
+
+    #[test]
+    fn lock() {
+        let (config, _) = Simulated::new();
+        {
+            let _lock = Lock::acquire(&config).unwrap();
+            let error = Lock::acquire(&config).unwrap_err();
+            assert!(error.to_string().starts_with("Database is locked"));
+        }
+        assert!(Lock::acquire(&config).is_ok());
+        {
+            let _lock = Lock::acquire(&config).unwrap();
+            let start = Instant::now();
+            assert!(Lock::acquire_within(&config, Duration::from_millis(200)).is_err());
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        }
+        assert!(Lock::acquire_within(&config, Duration::from_millis(200)).is_ok());
     }
 }
@@ -7,6 +7,13 @@
         unused_import_braces,
 )]
 
+// `Box<Error>`, `&'static str` statics, `field: field` and the short
+// slog macros are how this code base is written
+#![allow(bare_trait_objects, deprecated)]
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+// `JDB` and `NIC` are part of the API, saga steps return aud's `Failure`
+#![allow(clippy::upper_case_acronyms, clippy::result_large_err)]
+
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
@@ -28,6 +35,8 @@ extern crate chrono;
 extern crate tempfile;
 extern crate bzip2;
 extern crate flate2;
+extern crate libc;
+extern crate base64;
 
 //extern crate indicatif;
 
@@ -54,20 +63,20 @@ use std::fs::File;
 
 use aud::{Failure, Adventure, Saga};
 
-use std::process::Command;
-
+mod executor;
 mod zfs;
 mod images;
 mod jails;
 use jails::Jail;
 
+mod cidr;
 mod jail_config;
 mod update;
 
 use jail_config::JailConfig;
 
 mod jdb;
-use jdb::{JDB, IdxEntry};
+use jdb::{JDB, IdxEntry, JailState, Lock};
 
 mod config;
 use config::Config;
@@ -75,10 +84,11 @@ use config::Config;
 mod errors;
 use errors::GenericError;
 
-#[cfg(target_os = "freebsd")]
-static JEXEC: &'static str = "jexec";
-#[cfg(not(target_os = "freebsd"))]
-static JEXEC: &'static str = "echo";
+mod filter;
+use filter::Filter;
+
+mod metadata;
+
 
 
 /// Custom Drain logic
@@ -125,7 +135,7 @@ fn main() {
 
 #[cfg(not(target_os = "freebsd"))]
 fn main() {
-    println!("Jails are not supported, running in dummy mode");
+    println!("Jails are not supported, running in simulator mode");
     let exit_code = run();
     std::process::exit(exit_code)
 }
@@ -136,7 +146,7 @@ fn run() -> i32 {
     let mut help_app = App::from_yaml(yaml).version(crate_version!());
     let matches = App::from_yaml(yaml).version(crate_version!()).get_matches();
 
-    /// console logger
+    // console logger
     let decorator = slog_term::TermDecorator::new().build();
     let term_drain = slog_term::FullFormat::new(decorator).build().fuse();
     let level = matches.occurrences_of("verbose");
@@ -146,11 +156,10 @@ fn run() -> i32 {
     }.fuse();
     let term_drain = slog_async::Async::new(term_drain).build().fuse();
 
-    /// fiel logger
+    // fiel logger
     let log_path = "/var/log/vmadm.log";
     let file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(log_path)
         .unwrap();
@@ -177,6 +186,7 @@ fn run() -> i32 {
     } else {
         match matches.subcommand() {
             ("list", Some(list_matches)) => list(&config, list_matches),
+            ("lookup", Some(lookup_matches)) => lookup(&config, lookup_matches),
             ("create", Some(create_matches)) => create(&config, create_matches),
             ("update", Some(update_matches)) => update(&config, update_matches),
             ("delete", Some(delete_matches)) => delete(&config, delete_matches),
@@ -186,7 +196,11 @@ fn run() -> i32 {
             ("get", Some(get_matches)) => get(&config, get_matches),
             ("info", Some(info_matches)) => info(&config, info_matches),
             ("console", Some(console_matches)) => console(&config, console_matches),
+            ("exec", Some(exec_matches)) => exec(&config, exec_matches),
+            ("metadata", Some(metadata_matches)) => serve_metadata(&config, metadata_matches),
+            ("user-script", Some(script_matches)) => user_script(&config, script_matches),
             ("images", Some(image_matches)) => images(&config, image_matches),
+            ("db", Some(db_matches)) => db(&config, db_matches),
             ("config", Some(config_matches)) => hv_config(&config, config_matches),
 
             ("", None) => {
@@ -210,6 +224,10 @@ fn run() -> i32 {
 }
 
 fn startup(conf: &Config) -> Result<i32, Box<Error>> {
+    let _lock = Lock::acquire(conf)?;
+    for change in jdb::migrate::legacy_dir(conf, false)? {
+        info!("Migrated legacy database: {}", change);
+    }
     let db = JDB::open(conf)?;
     for e in db.iter() {
         let jail = db.get(&e.uuid)?;
@@ -225,9 +243,12 @@ fn startup(conf: &Config) -> Result<i32, Box<Error>> {
 }
 
 fn start(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    if matches.is_present("tag") {
+        return bulk(conf, matches, true);
+    }
+    let _lock = Lock::acquire(conf)?;
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("Starting jail {}", uuid.hyphenated());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -243,9 +264,9 @@ fn start(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
 }
 
 fn reboot(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let _lock = Lock::acquire(conf)?;
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("deleteing jail {}", uuid.hyphenated());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -255,7 +276,8 @@ fn reboot(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
         }
         Ok(jail) => {
             println!("Rebooting jail {}", uuid);
-            jail.stop()?;
+            let shutdown = jail.stop(conf, matches.is_present("force"))?;
+            println!("Stopped jail {} ({})", uuid, shutdown);
             jail.start(conf)
         }
     }
@@ -263,7 +285,7 @@ fn reboot(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
 
 fn get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid = value_t!(matches, "uuid", Uuid).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("Starting jail {}", uuid.hyphenated().to_string());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -277,15 +299,23 @@ fn get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
 
 fn info(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("Getting jail info {}", uuid.hyphenated());
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(_jail) => {
-            println!("Unable to get info for jail.\n");
-            Ok(0)
-        }
+    let info = db.get(&uuid)?.info(conf)?;
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(0)
+}
+
+fn serve_metadata(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = JDB::open(conf)?.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
+    metadata::serve(conf, &uuid)
+}
+
+fn user_script(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let uuid = JDB::open(conf)?.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
+    match jails::run_user_script(conf, &uuid)? {
+        Some(result) => Ok(result.exit_code.unwrap_or(0)),
+        None => Ok(0),
     }
 }
 
@@ -296,7 +326,7 @@ struct Info {
 
 fn hv_config(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let info = Info{
-        networks: conf.settings.networks.keys().map(|v| v.clone()).collect::<Vec<String>>()
+        networks: conf.settings.networks.keys().cloned().collect::<Vec<String>>()
     };
     debug!("Getting hypervisor info.");
     let j = serde_json::to_string_pretty(&info)?;
@@ -306,34 +336,35 @@ fn hv_config(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Erro
 
 fn console(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("Starting jail {}", uuid.hyphenated());
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(Jail { inner: None, .. }) => {
-            println!("The vm is not running");
-            Err(GenericError::bx("VM is not running"))
-        }
-        Ok(Jail { inner: Some(jid), .. }) => {
-            let mut child = Command::new(JEXEC)
-                .args(&[jid.id.to_string().as_str(), "/bin/csh"])
-                .spawn()
-                .expect("failed to execute jexec");
-            let ecode = child.wait().expect("failed to wait on child");
-            if ecode.success() {
-                Ok(0)
-            } else {
-                Err(GenericError::bx("Failed to execute jail console"))
-            }
-        }
+    let jail = db.get(&uuid)?;
+    let shell = jail.shell(conf);
+    let ecode = jail.exec(conf, None, &[], &[shell.as_str()])?;
+    if ecode == 0 {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to execute jail console"))
     }
 }
 
+fn exec(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let db = JDB::open(conf)?;
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
+    let env: Vec<&str> = matches.values_of("env").map(|v| v.collect()).unwrap_or_default();
+    let cmd: Vec<&str> = matches.values_of("cmd").map(|v| v.collect()).unwrap_or_default();
+    debug!("Executing in jail {}", uuid.hyphenated());
+    let jail = db.get(&uuid)?;
+    jail.exec(conf, matches.value_of("user"), &env, &cmd)
+}
+
 fn stop(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    if matches.is_present("tag") {
+        return bulk(conf, matches, false);
+    }
+    let _lock = Lock::acquire(conf)?;
     let db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("stopping jail {}", uuid.hyphenated());
     match db.get(&uuid) {
         Err(e) => Err(e),
@@ -343,23 +374,160 @@ fn stop(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
         }
         Ok(jail) => {
             println!("Stopping jail {}", uuid);
-            jail.stop()
+            let shutdown = jail.stop(conf, matches.is_present("force"))?;
+            println!("Stopped jail {} ({})", uuid, shutdown);
+            Ok(0)
+        }
+    }
+}
+
+/// Starts or stops all jails matching every `--tag`, jails that are
+/// already in the wanted state are skipped and failures do not stop the
+/// remaining jails from being handled.
+fn bulk(conf: &Config, matches: &clap::ArgMatches, start: bool) -> Result<i32, Box<Error>> {
+    let mut filters = Vec::new();
+    if let Some(tags) = matches.values_of("tag") {
+        for tag in tags {
+            filters.push(Filter::tag(tag)?);
         }
     }
+    let _lock = Lock::acquire(conf)?;
+    let db = JDB::open(conf)?;
+    let mut failed = 0;
+    for e in db.iter() {
+        let jail = db.get(&e.uuid)?;
+        let obj = jail.json()?;
+        if !filters.iter().all(|f| f.matches(&obj)) || jail.outer.is_some() == start {
+            continue;
+        }
+        let uuid = jail.idx.uuid;
+        let res = if start {
+            println!("Starting jail {}", uuid);
+            jail.start(conf)
+        } else {
+            println!("Stopping jail {}", uuid);
+            jail.stop(conf, matches.is_present("force")).map(|shutdown| {
+                println!("Stopped jail {} ({})", uuid, shutdown);
+                0
+            })
+        };
+        if let Err(e) = res {
+            println!("Failed jail {}: {}", uuid, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(GenericError::bx(format!("{} jails failed", failed).as_str()));
+    }
+    Ok(0)
 }
 
 fn list(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
     let db = JDB::open(conf)?;
+    let fields: Vec<&str> = match matches.value_of("output") {
+        Some(output) => output.split(',').collect(),
+        None => jdb::DEFAULT_COLUMNS.to_vec(),
+    };
+    let sort: Vec<&str> = match matches.value_of("sort") {
+        Some(sort) => sort.split(',').collect(),
+        None => Vec::new(),
+    };
+    let mut filters = Vec::new();
+    if let Some(exprs) = matches.values_of("filters") {
+        for expr in exprs {
+            filters.push(Filter::parse(expr)?);
+        }
+    }
     db.print(
         matches.is_present("headerless"),
         matches.is_present("parsable"),
+        &fields,
+        &sort,
+        &filters,
     )
 }
 
+fn lookup(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let db = JDB::open(conf)?;
+    let mut filters = Vec::new();
+    if let Some(exprs) = matches.values_of("filters") {
+        for expr in exprs {
+            filters.push(Filter::parse(expr)?);
+        }
+    }
+    let fields: Option<Vec<&str>> = matches.value_of("output").map(|o| o.split(',').collect());
+    let mut found = Vec::new();
+    for e in db.iter() {
+        let jail = db.get(&e.uuid)?;
+        let obj = jail.json()?;
+        if filters.iter().all(|f| f.matches(&obj)) {
+            found.push(obj);
+        }
+    }
+    debug!("Lookup done"; "matches" => found.len());
+    if matches.is_present("unique") && found.len() != 1 {
+        return Err(GenericError::bx(
+            format!("Requested unique lookup but found {} results", found.len()).as_str(),
+        ));
+    }
+    let found: Vec<serde_json::Value> = match fields {
+        None => found,
+        Some(ref fields) => {
+            found
+                .iter()
+                .map(|obj| {
+                    let mut res = serde_json::Map::new();
+                    for field in fields.iter() {
+                        let mut values = filter::lookup(obj, field);
+                        let value = if values.len() == 1 && !field.contains('*') {
+                            values.remove(0).clone()
+                        } else {
+                            serde_json::Value::Array(values.into_iter().cloned().collect())
+                        };
+                        res.insert(String::from(*field), value);
+                    }
+                    serde_json::Value::Object(res)
+                })
+                .collect()
+        }
+    };
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+    } else if let Some(fields) = fields {
+        for obj in found.iter() {
+            let values: Vec<String> = fields
+                .iter()
+                .map(|field| match obj[*field] {
+                    serde_json::Value::Array(ref a) => {
+                        a.iter()
+                            .filter_map(filter::to_string)
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    }
+                    ref v => filter::to_string(v).unwrap_or_default(),
+                })
+                .collect();
+            println!("{}", values.join(":"));
+        }
+    } else {
+        for obj in found.iter() {
+            if let Some(uuid) = obj.get("uuid").and_then(|u| u.as_str()) {
+                println!("{}", uuid);
+            }
+        }
+    }
+    Ok(0)
+}
+
 fn update(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let mut db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let dry_run = matches.is_present("dry-run");
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(Lock::acquire(conf)?)
+    };
+    let db = JDB::open(conf)?;
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     let update = match value_t!(matches, "file", String) {
         Err(_) => {
             debug!("Reading from STDIN");
@@ -370,29 +538,67 @@ fn update(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
             update::JailUpdate::from_reader(File::open(file)?)?
         }
     };
-    match db.get(&uuid) {
-        Err(e) => Err(e),
-        Ok(Jail { config: c, .. }) => {
-            let c = update.apply(c);
-            db.update(c)
+    let (new, applied) = {
+        let jail = db.get(&uuid)?;
+        let new = update.apply_checked(conf, jail.config.clone())?;
+        if dry_run {
+            let changes = update::diff(&jail.config, &new)?;
+            if changes.is_empty() {
+                println!("No changes to jail {}", uuid);
+            } else {
+                println!("Would update jail {}:", uuid);
+            }
+            for change in changes {
+                println!("  {}", change);
+            }
+            return Ok(0);
         }
+        let applied = jail.apply_update(conf, &new)?;
+        (new, applied)
+    };
+    // reload so nothing written while applying the update is lost
+    let mut db = JDB::open(conf)?;
+    if let Err(e) = db.update(new.clone()) {
+        let db = JDB::open(conf)?;
+        if let Err(revert) = db.get(&uuid).and_then(|jail| jail.revert_update(conf, &new)) {
+            crit!("failed to revert update"; "vm" => uuid.hyphenated().to_string(),
+                  "error" => revert.to_string());
+        }
+        return Err(e);
+    }
+    println!("Updated jail {}", uuid);
+    if !applied.live.is_empty() {
+        println!("Applied live: {}", applied.live.join(", "));
+    }
+    if !applied.restart.is_empty() {
+        println!("Requires a restart: {}", applied.restart.join(", "));
     }
+    Ok(0)
 }
 
 fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let dry_run = matches.is_present("dry-run");
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(Lock::acquire(conf)?)
+    };
     let jail = match value_t!(matches, "file", String) {
         Err(_) => {
             debug!("Reading from STDIN");
-            jail_config::JailConfig::from_reader(conf, io::stdin())?
+            jail_config::JailConfig::from_input(conf, io::stdin())?
         }
         Ok(file) => {
             debug!("Reading from file"; "file" => file.clone() );
-            jail_config::JailConfig::from_reader(conf, File::open(file)?)?
+            jail_config::JailConfig::from_input(conf, File::open(file)?)?
         }
     };
     let mut dataset = conf.settings.pool.clone();
     dataset.push('/');
     dataset.push_str(jail.image_uuid.hyphenated().to_string().as_str());
+    if dry_run {
+        return create_plan(conf, &jail, dataset.as_str());
+    }
 
     struct CreateState<'a> {
         conf: &'a Config,
@@ -406,7 +612,7 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
 
     let state = CreateState {
         conf,
-        uuid: jail.uuid.clone(),
+        uuid: jail.uuid,
         dataset,
         config: jail,
         entry: None,
@@ -431,7 +637,7 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
             }
             Err(error) => Err(Failure::new(state, error)),
         }
-    };
+    }
     fn insert_down(state: CreateState) -> CreateState {
         crit!("Rolling back insert");
         match JDB::open(state.conf) {
@@ -441,10 +647,11 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
             Err(_error) => (),
         };
         state
-    };
+    }
 
     fn snap_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
         match zfs::snapshot(
+            state.conf,
             state.dataset.as_str(),
             state.uuid.hyphenated().to_string().as_str(),
         ) {
@@ -464,7 +671,7 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
         crit!("Rolling back snapshot");
         match state.snapshot.clone() {
             Some(snap) => {
-                let _ = zfs::destroy(snap.as_str());
+                let _ = zfs::destroy(state.conf, snap.as_str());
                 state
             }
             None => state,
@@ -476,7 +683,7 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
             Some(snap) => {
                 match state.entry.clone() {
                     Some(entry) => {
-                        match zfs::clone(snap.as_str(), entry.root.as_str()) {
+                        match zfs::clone(state.conf, snap.as_str(), entry.root.as_str()) {
                             Ok(_) => Ok(CreateState {
                                 conf: state.conf,
                                 uuid: state.uuid,
@@ -499,16 +706,119 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
         crit!("Rolling back clone");
         match state.root.clone() {
             Some(root) => {
-                let _ = zfs::destroy(root.as_str());
+                let _ = zfs::destroy(state.conf, root.as_str());
                 state
             }
             None => state,
         }
     }
+
+    fn quota_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let res = match state.root.clone() {
+            Some(root) => jails::set_quota(state.conf, root.as_str(), state.config.quota),
+            None => Err(GenericError::bx("No root to set the quota on")),
+        };
+        match res {
+            Ok(_) => Ok(state),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn quota_down(state: CreateState) -> CreateState {
+        crit!("Rolling back quota");
+        if let Some(root) = state.root.clone() {
+            let _ = zfs::set_property(state.conf, root.as_str(), "quota", "none");
+        }
+        state
+    }
+
+    fn delegate_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let res = match state.root.clone() {
+            Some(_) if !state.config.delegate_dataset => Ok(0),
+            Some(root) => {
+                let dataset = jails::delegated_dataset(root.as_str());
+                zfs::create(state.conf, dataset.as_str(), &["jailed=on"])
+            }
+            None => Err(GenericError::bx("No root to delegate a dataset from")),
+        };
+        match res {
+            Ok(_) => Ok(state),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn delegate_down(state: CreateState) -> CreateState {
+        crit!("Rolling back delegated dataset");
+        if let Some(root) = state.root.clone() {
+            if state.config.delegate_dataset {
+                let _ = zfs::destroy(state.conf, jails::delegated_dataset(root.as_str()).as_str());
+            }
+        }
+        state
+    }
+
+    fn resolvers_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let res = match state.root.clone() {
+            Some(root) => jails::write_resolv_conf(root.as_str(), &state.config),
+            None => Err(GenericError::bx("No root to write resolv.conf to")),
+        };
+        match res {
+            Ok(_) => Ok(state),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn resolvers_down(state: CreateState) -> CreateState {
+        state
+    }
+
+    fn indestructible_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let res = match state.root.clone() {
+            Some(root) => {
+                let delegated = jails::delegated_dataset(root.as_str());
+                let zoneroot = state.config.indestructible_zoneroot == Some(true);
+                let data = state.config.delegate_dataset &&
+                    state.config.indestructible_delegated == Some(true);
+                jails::set_indestructible(state.conf, root.as_str(), zoneroot).and_then(|_| {
+                    jails::set_indestructible(state.conf, delegated.as_str(), data)
+                })
+            }
+            None => Err(GenericError::bx("No root to make indestructible")),
+        };
+        match res {
+            Ok(_) => Ok(state),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn indestructible_down(state: CreateState) -> CreateState {
+        crit!("Rolling back indestructible");
+        if let Some(root) = state.root.clone() {
+            let delegated = jails::delegated_dataset(root.as_str());
+            let _ = jails::set_indestructible(state.conf, delegated.as_str(), false);
+            let _ = jails::set_indestructible(state.conf, root.as_str(), false);
+        }
+        state
+    }
+
+    fn provisioned_up(state: CreateState) -> Result<CreateState, Failure<CreateState>> {
+        let res = match JDB::open(state.conf) {
+            Ok(mut db) => db.set_state(&state.uuid, JailState::Stopped),
+            Err(error) => Err(error),
+        };
+        match res {
+            Ok(_) => Ok(state),
+            Err(error) => Err(Failure::new(state, error)),
+        }
+    }
+    fn provisioned_down(state: CreateState) -> CreateState {
+        state
+    }
     let saga = Saga::new(vec![
         Adventure::new(insert_up, insert_down),
         Adventure::new(snap_up, snap_down),
         Adventure::new(clone_up, clone_down),
+        Adventure::new(quota_up, quota_down),
+        Adventure::new(delegate_up, delegate_down),
+        Adventure::new(resolvers_up, resolvers_down),
+        Adventure::new(indestructible_up, indestructible_down),
+        Adventure::new(provisioned_up, provisioned_down),
     ]);
     match saga.tell(state) {
         Ok(state) => {
@@ -519,45 +829,182 @@ fn create(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>>
     }
 }
 
+/// Prints what `create` would do without changing anything
+fn create_plan(conf: &Config, jail: &JailConfig, dataset: &str) -> Result<i32, Box<Error>> {
+    let db = JDB::open(conf)?;
+    if db.get(&jail.uuid).is_ok() {
+        return Err(errors::ConflictError::bx(&jail.uuid));
+    }
+    if !zfs::is_present(conf, dataset) {
+        return Err(GenericError::bx(
+            format!("Image dataset {} does not exist", dataset).as_str(),
+        ));
+    }
+    let uuid = jail.uuid.hyphenated().to_string();
+    let snapshot = format!("{}@{}", dataset, uuid);
+    let root = format!("{}/{}", conf.settings.pool, uuid);
+    println!("Would create jail {}:", uuid);
+    println!("{}", serde_json::to_string_pretty(jail)?);
+    println!("Planned operations:");
+    println!("  write {}", db.config_path(&jail.uuid).display());
+    println!("  add {} to the index as provisioning", uuid);
+    println!("  zfs snapshot {}", snapshot);
+    println!("  zfs clone {} {}", snapshot, root);
+    if jail.quota == 0 {
+        println!("  zfs set quota=none {}", root);
+    } else {
+        println!("  zfs set quota={}G {}", jail.quota, root);
+    }
+    if jail.delegate_dataset {
+        println!("  zfs create -u -o jailed=on {}", jails::delegated_dataset(root.as_str()));
+    }
+    if !jail.resolvers.is_empty() {
+        println!("  write /{}/root/jail/etc/resolv.conf", root);
+    }
+    if jail.indestructible_zoneroot == Some(true) {
+        println!("  zfs snapshot {}@indestructible and hold it", root);
+    }
+    if jail.delegate_dataset && jail.indestructible_delegated == Some(true) {
+        println!(
+            "  zfs snapshot {}@indestructible and hold it",
+            jails::delegated_dataset(root.as_str())
+        );
+    }
+    println!("  mark {} as stopped", uuid);
+    Ok(0)
+}
+
 fn delete(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let mut db = JDB::open(conf)?;
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    if matches.is_present("dry-run") {
+        return delete_plan(conf, matches);
+    }
+    let _lock = Lock::acquire(conf)?;
+    let db = JDB::open(conf)?;
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
     debug!("deleteing jail {}", uuid.hyphenated());
     let res = match db.get(&uuid) {
         Ok(jail) => {
+            check_destructible(&jail.config)?;
             if jail.outer.is_some() {
                 println!("Stopping jail {}", uuid);
-                jail.stop()?;
-            };
-            let origin = zfs::origin(jail.idx.root.as_str());
-            match zfs::destroy(jail.idx.root.as_str()) {
-                Ok(_) => debug!("zfs dataset deleted: {}", jail.idx.root),
-                Err(e) => warn!("failed to delete dataset: {}", e),
-            };
-            match origin {
-                Ok(origin) => {
-                    zfs::destroy(origin.as_str())?;
-                    debug!("zfs snapshot deleted: {}", origin)
-                }
-                Err(e) => warn!("failed to delete origin: {}", e),
+                jail.stop(conf, true)?;
+            } else {
+                jail.reconcile(conf)?;
             };
+            jail.transition(conf, JailState::Deleting)?;
+            // the entry is kept so the delete can be retried
+            if let Err(e) = destroy_datasets(conf, &jail) {
+                let _ = jail.transition(conf, JailState::Failed);
+                return Err(e);
+            }
             println!("deleted jail {}", uuid);
             Ok(0)
         }
         Err(e) => Err(e),
     };
+    // the jail went through several states since the index was loaded
+    let mut db = JDB::open(conf)?;
     db.remove(&uuid)?;
     res
 }
 
+/// Destroys the datasets of a jail along with the snapshot it was cloned
+/// from, a root that is already gone is skipped
+fn destroy_datasets(conf: &Config, jail: &Jail) -> Result<(), Box<Error>> {
+    if !zfs::is_present(conf, jail.idx.root.as_str()) {
+        warn!("jail dataset is already deleted: {}", jail.idx.root);
+        return Ok(());
+    }
+    let origin = zfs::origin(conf, jail.idx.root.as_str())?;
+    if jail.config.delegate_dataset {
+        let dataset = jails::delegated_dataset(jail.idx.root.as_str());
+        if zfs::get(conf, dataset.as_str()).is_ok() {
+            zfs::destroy(conf, dataset.as_str())?;
+            debug!("delegated dataset deleted: {}", dataset);
+        }
+    }
+    zfs::destroy(conf, jail.idx.root.as_str())?;
+    debug!("zfs dataset deleted: {}", jail.idx.root);
+    if origin != "-" {
+        zfs::destroy(conf, origin.as_str())?;
+        debug!("zfs snapshot deleted: {}", origin);
+    }
+    Ok(())
+}
+
+/// Refuses to delete jails that have one of the indestructible flags set
+fn check_destructible(jail: &JailConfig) -> Result<(), Box<Error>> {
+    if jail.indestructible_zoneroot == Some(true) {
+        return Err(GenericError::bx(
+            "The jail root is indestructible, clear indestructible_zoneroot first",
+        ));
+    }
+    if jail.delegate_dataset && jail.indestructible_delegated == Some(true) {
+        return Err(GenericError::bx(
+            "The delegated dataset is indestructible, clear indestructible_delegated first",
+        ));
+    }
+    Ok(())
+}
+
+/// Prints what `delete` would remove without changing anything
+fn delete_plan(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let db = JDB::open(conf)?;
+    let uuid = db.resolve(value_t!(matches, "uuid", String).unwrap().as_str())?;
+    let jail = db.get(&uuid)?;
+    check_destructible(&jail.config)?;
+    println!("Would delete jail {}:", uuid);
+    if jail.outer.is_some() {
+        println!("  stop the running jail");
+    }
+    if jail.config.delegate_dataset {
+        println!("  zfs destroy {}", jails::delegated_dataset(jail.idx.root.as_str()));
+    }
+    println!("  zfs destroy {}", jail.idx.root);
+    match zfs::origin(conf, jail.idx.root.as_str()) {
+        Ok(ref origin) if origin != "-" => println!("  zfs destroy {}", origin),
+        _ => (),
+    }
+    println!("  remove {}", db.config_path(&uuid).display());
+    println!("  remove {} from the index", uuid);
+    Ok(0)
+}
+
+fn db(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    match matches.subcommand() {
+        ("migrate", Some(migrate_matches)) => db_migrate(conf, migrate_matches),
+        ("", None) => Ok(0),
+        _ => unreachable!(),
+    }
+}
+
+fn db_migrate(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
+    let dry_run = matches.is_present("dry-run");
+    let mut changes = Config::migrate(dry_run)?;
+    if dry_run {
+        changes.append(&mut jdb::migrate::run(conf, dry_run)?);
+    } else {
+        let _lock = Lock::acquire(conf)?;
+        changes.append(&mut jdb::migrate::run(conf, dry_run)?);
+    }
+    if changes.is_empty() {
+        println!("Database is up to date");
+    } else if dry_run {
+        println!("The following changes would be made:");
+    }
+    for change in changes {
+        println!("  {}", change);
+    }
+    Ok(0)
+}
+
 fn images(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
         match matches.subcommand() {
-            ("avail", Some(avail_matches)) => images_avail(&conf, avail_matches),
-            ("list", Some(list_matches)) => images_list(&conf, list_matches),
-            ("get", Some(get_matches)) => images_get(&conf, get_matches),
-            ("show", Some(show_matches)) => images_show(&conf, show_matches),
-            ("import", Some(import_matches)) => images_import(&conf, import_matches),
+            ("avail", Some(avail_matches)) => images_avail(conf, avail_matches),
+            ("list", Some(list_matches)) => images_list(conf, list_matches),
+            ("get", Some(get_matches)) => images_get(conf, get_matches),
+            ("show", Some(show_matches)) => images_show(conf, show_matches),
+            ("import", Some(import_matches)) => images_import(conf, import_matches),
             ("", None) => {
                 Ok(0)
             }
@@ -574,19 +1021,16 @@ fn images_list(conf: &Config, _matches: &clap::ArgMatches) -> Result<i32, Box<Er
 }
 
 fn images_get(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = images::resolve_installed(conf, value_t!(matches, "uuid", String).unwrap().as_str())?;
     images::get(conf, uuid)
 }
 
 fn images_show(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = images::resolve_avail(conf, value_t!(matches, "uuid", String).unwrap().as_str())?;
     images::show(conf, uuid)
 }
 
 fn images_import(conf: &Config, matches: &clap::ArgMatches) -> Result<i32, Box<Error>> {
-    let uuid_string = value_t!(matches, "uuid", String).unwrap();
-    let uuid = Uuid::parse_str(uuid_string.as_str()).unwrap();
+    let uuid = images::resolve_avail(conf, value_t!(matches, "uuid", String).unwrap().as_str())?;
     images::import(conf, uuid)
 }
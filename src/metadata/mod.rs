@@ -0,0 +1,307 @@
+//! Metadata service that exposes a jails `customer_metadata` and
+//! `internal_metadata` over a unix socket inside the jail
+
+use std::env;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use libc;
+use serde_json;
+use uuid::Uuid;
+
+use config::Config;
+use errors::GenericError;
+use jail_config::JailConfig;
+use jdb::{JDB, Lock};
+
+mod protocol;
+pub use self::protocol::Store;
+
+static DAEMON: &'static str = "daemon";
+static PKILL: &'static str = "pkill";
+
+/// Seconds an idle connection is kept open
+static READ_TIMEOUT: u64 = 30;
+/// Seconds a change waits for other vmadm commands to release the
+/// database
+static LOCK_TIMEOUT: u64 = 10;
+
+impl Store for JailConfig {
+    /// `sdc:` keys describe the jail, keys in one of the
+    /// `internal_metadata_namespaces` come from `internal_metadata` and
+    /// everything else from `customer_metadata`.
+    fn get(&self, key: &str) -> Result<Option<String>, Box<Error>> {
+        let value = match key {
+            "sdc:uuid" => Some(self.uuid.hyphenated().to_string()),
+            "sdc:hostname" => Some(self.hostname.clone()),
+            "sdc:alias" => Some(self.alias.clone()),
+            "sdc:dns_domain" => Some(self.dns_domain.clone()),
+            "sdc:image_uuid" => Some(self.image_uuid.hyphenated().to_string()),
+            "sdc:resolvers" => Some(serde_json::to_string(&self.resolvers)?),
+            "sdc:nics" => Some(serde_json::to_string(&self.nics)?),
+            "sdc:routes" => Some(serde_json::to_string(&self.routes)?),
+            _ if self.is_internal(key) => self.internal_metadata.get(key).cloned(),
+            _ => self.customer_metadata.get(key).cloned(),
+        };
+        Ok(value)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Box<Error>> {
+        Ok(self.customer_metadata.keys().cloned().collect())
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> Result<(), Box<Error>> {
+        if self.is_internal(key) {
+            return Err(GenericError::bx("internal metadata is read only"));
+        }
+        self.customer_metadata.insert(String::from(key), String::from(value));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Box<Error>> {
+        if self.is_internal(key) {
+            return Err(GenericError::bx("internal metadata is read only"));
+        }
+        self.customer_metadata.remove(key);
+        Ok(())
+    }
+}
+
+impl JailConfig {
+    /// If a key belongs to the read only `sdc:` or internal namespaces
+    fn is_internal(&self, key: &str) -> bool {
+        match key.find(':') {
+            Some(pos) => {
+                let namespace = &key[..pos];
+                namespace == "sdc" ||
+                    self.internal_metadata_namespaces.iter().any(
+                        |ns| ns == namespace,
+                    )
+            }
+            None => false,
+        }
+    }
+}
+
+/// Store that reads the jails config for every request and saves changes
+/// back to the database
+struct JailStore<'a> {
+    config: &'a Config,
+    uuid: Uuid,
+}
+
+impl<'a> JailStore<'a> {
+    fn load(&self) -> Result<JailConfig, Box<Error>> {
+        let db = JDB::open(self.config)?;
+        let jail = db.get(&self.uuid)?;
+        Ok(jail.config.clone())
+    }
+
+    fn change<F>(&self, f: F) -> Result<(), Box<Error>>
+    where
+        F: FnOnce(&mut JailConfig) -> Result<(), Box<Error>>,
+    {
+        let _lock = Lock::acquire_within(self.config, Duration::from_secs(LOCK_TIMEOUT))?;
+        let mut jail = self.load()?;
+        f(&mut jail)?;
+        let mut db = JDB::open(self.config)?;
+        db.update(jail)?;
+        Ok(())
+    }
+}
+
+impl<'a> Store for JailStore<'a> {
+    fn get(&self, key: &str) -> Result<Option<String>, Box<Error>> {
+        self.load()?.get(key)
+    }
+    fn keys(&self) -> Result<Vec<String>, Box<Error>> {
+        self.load()?.keys()
+    }
+    fn put(&mut self, key: &str, value: &str) -> Result<(), Box<Error>> {
+        self.change(|jail| jail.put(key, value))
+    }
+    fn delete(&mut self, key: &str) -> Result<(), Box<Error>> {
+        self.change(|jail| jail.delete(key))
+    }
+}
+
+/// Path of the metadata socket for a jail, the same place mdata-client
+/// looks for it in SmartOS zones
+pub fn socket_path(root: &str, brand: &str) -> String {
+    let dir = if brand == "lx-jail" {
+        "native/.zonecontrol"
+    } else {
+        ".zonecontrol"
+    };
+    format!("/{}/root/jail/{}/metadata.sock", root, dir)
+}
+
+fn pid_file(uuid: &Uuid) -> String {
+    format!("/var/run/vmadm-metadata-{}.pid", uuid.hyphenated())
+}
+
+/// Starts `vmadm metadata <uuid>` in the background
+pub fn start(config: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let exe = env::current_exe()?;
+    let exe = exe.to_string_lossy();
+    let pid_file = pid_file(uuid);
+    let uuid = uuid.hyphenated().to_string();
+    debug!("starting metadata service"; "vm" => uuid.clone());
+    let output = config.executor.run(
+        DAEMON,
+        &[
+            "-f",
+            "-p",
+            pid_file.as_str(),
+            exe.as_ref(),
+            "metadata",
+            uuid.as_str(),
+        ],
+    )?;
+    if !output.success() {
+        return Err(GenericError::bx("could not start metadata service"));
+    }
+    Ok(0)
+}
+
+/// Stops the metadata service of a jail
+pub fn stop(config: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let pid_file = pid_file(uuid);
+    debug!("stopping metadata service"; "vm" => uuid.hyphenated().to_string());
+    let output = config.executor.run(PKILL, &["-F", pid_file.as_str()])?;
+    if !output.success() {
+        return Err(GenericError::bx("could not stop metadata service"));
+    }
+    Ok(0)
+}
+
+/// Serves the metadata socket of a jail until the process is killed,
+/// connections are handled one at a time.
+pub fn serve(config: &Config, uuid: &Uuid) -> Result<i32, Box<Error>> {
+    let path = {
+        let db = JDB::open(config)?;
+        let jail = db.get(uuid)?;
+        socket_path(jail.idx.root.as_str(), jail.config.brand.as_str())
+    };
+    let (dir, name) = match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => return Err(GenericError::bx("invalid socket path")),
+    };
+    // the jail owns its root, bind relative to the directory so the path
+    // is not resolved again after it was checked
+    let dir = open_dir(dir)?;
+    let c_name = CString::new(name)?;
+    unsafe {
+        libc::unlinkat(dir.as_raw_fd(), c_name.as_ptr(), 0);
+    }
+    if unsafe { libc::fchdir(dir.as_raw_fd()) } != 0 {
+        return Err(Box::new(io::Error::last_os_error()));
+    }
+    let listener = UnixListener::bind(name)?;
+    debug!("serving metadata"; "vm" => uuid.hyphenated().to_string(), "socket" => path);
+    let mut store = JailStore {
+        config: config,
+        uuid: *uuid,
+    };
+    for stream in listener.incoming() {
+        let res = stream.map_err(|e| Box::new(e) as Box<Error>).and_then(
+            |stream| {
+                handle_connection(&mut store, stream)
+            },
+        );
+        if let Err(e) = res {
+            warn!("metadata connection failed"; "vm" => uuid.hyphenated().to_string(),
+                  "error" => e.to_string());
+        }
+    }
+    Ok(0)
+}
+
+fn handle_connection(store: &mut Store, stream: UnixStream) -> Result<(), Box<Error>> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let response = protocol::handle(store, line?.as_str());
+        writer.write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Opens a directory one component at a time from `/`, creating the
+/// missing ones. Symlinks are refused on the way as the jail controls
+/// part of the path.
+fn open_dir(path: &str) -> Result<File, Box<Error>> {
+    let root = CString::new("/")?;
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let fd = unsafe { libc::open(root.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Box::new(io::Error::last_os_error()));
+    }
+    let mut dir = unsafe { File::from_raw_fd(fd) };
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if component == ".." {
+            return Err(GenericError::bx("invalid socket path"));
+        }
+        let name = CString::new(component)?;
+        unsafe {
+            libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755);
+        }
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW) };
+        if fd < 0 {
+            let error = io::Error::last_os_error();
+            return Err(GenericError::bx(
+                format!("Can not open {} in {}: {}", component, path, error).as_str(),
+            ));
+        }
+        dir = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use metadata::*;
+
+    #[test]
+    fn namespaces() {
+        let mut jail: JailConfig = serde_json::from_str(
+            r##"{"image_uuid": "00000000-0000-0000-0000-000000000000",
+                "alias": "test", "hostname": "test", "max_physical_memory": 1024,
+                "cpu_cap": 100, "quota": 5,
+                "customer_metadata": {"user-script": "#!/bin/sh"},
+                "internal_metadata": {"operator:note": "hi"},
+                "internal_metadata_namespaces": ["operator"]}"##,
+        ).unwrap();
+        assert_eq!(Some(String::from("test")), jail.get("sdc:hostname").unwrap());
+        assert_eq!(Some(String::from("hi")), jail.get("operator:note").unwrap());
+        assert_eq!(Some(String::from("#!/bin/sh")), jail.get("user-script").unwrap());
+        assert!(jail.put("operator:note", "changed").is_err());
+        assert!(jail.delete("sdc:alias").is_err());
+        jail.put("user-data", "data").unwrap();
+        assert_eq!(vec!["user-data", "user-script"], jail.keys().unwrap());
+    }
+
+    #[test]
+    fn symlinks() {
+        use std::os::unix::fs::symlink;
+        use config::TempDir;
+        let tmp = TempDir::new();
+        let root = tmp.path().join("jail");
+        fs::create_dir(&root).unwrap();
+        let dir = root.join(".zonecontrol");
+        assert!(open_dir(dir.to_str().unwrap()).is_ok());
+        assert!(dir.is_dir());
+        symlink("/etc", root.join("native")).unwrap();
+        let escaped = root.join("native/.zonecontrol");
+        assert!(open_dir(escaped.to_str().unwrap()).is_err());
+        assert!(!Path::new("/etc/.zonecontrol").exists());
+    }
+}
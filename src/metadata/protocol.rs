@@ -0,0 +1,239 @@
+//! The SmartOS metadata protocol as spoken by `mdata-get`, `mdata-put`,
+//! `mdata-list` and `mdata-delete`.
+//!
+//! Version 1 requests are plain lines like `GET key`, version 2 is
+//! negotiated with `NEGOTIATE V2` and frames every request and response as
+//! `V2 <length> <crc32> <request id> <code> [base64 payload]`.
+
+use std::error::Error;
+
+use base64;
+
+use errors::GenericError;
+
+/// Where the protocol reads and writes metadata
+pub trait Store {
+    /// Value of a key, `None` if it does not exist
+    fn get(&self, key: &str) -> Result<Option<String>, Box<Error>>;
+    /// Keys that are listed by `mdata-list`
+    fn keys(&self) -> Result<Vec<String>, Box<Error>>;
+    /// Sets a key
+    fn put(&mut self, key: &str, value: &str) -> Result<(), Box<Error>>;
+    /// Removes a key
+    fn delete(&mut self, key: &str) -> Result<(), Box<Error>>;
+}
+
+/// Result of a request
+enum Reply {
+    Success(Option<String>),
+    NotFound,
+    Failure,
+}
+
+/// Handles a single request line and returns the response including the
+/// trailing newline
+pub fn handle(store: &mut Store, line: &str) -> String {
+    let line = line.trim();
+    if line == "NEGOTIATE V2" {
+        return String::from("V2_OK\n");
+    }
+    if line.starts_with("V2 ") {
+        return handle_v2(store, line);
+    }
+    handle_v1(store, line)
+}
+
+fn handle_v1(store: &mut Store, line: &str) -> String {
+    let mut parts = line.splitn(2, ' ');
+    let reply = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(key)) => get(store, key),
+        (Some("KEYS"), None) => keys(store),
+        _ => return String::from("invalid command\n"),
+    };
+    match reply {
+        Reply::Success(value) => {
+            let mut res = String::from("SUCCESS\n");
+            for line in value.unwrap_or_default().lines() {
+                // lines starting with a dot are escaped with a second one
+                if line.starts_with('.') {
+                    res.push('.');
+                }
+                res.push_str(line);
+                res.push('\n');
+            }
+            res.push_str(".\n");
+            res
+        }
+        Reply::NotFound => String::from("NOTFOUND\n"),
+        Reply::Failure => String::from("FAILURE\n"),
+    }
+}
+
+fn handle_v2(store: &mut Store, line: &str) -> String {
+    let (id, code, payload) = match parse_v2(line) {
+        Ok(request) => request,
+        Err(_) => return String::from("invalid command\n"),
+    };
+    let payload = payload.and_then(|p| base64::decode(p).ok()).and_then(
+        |p| String::from_utf8(p).ok(),
+    );
+    let reply = match (code, payload) {
+        ("GET", Some(key)) => get(store, key.as_str()),
+        ("KEYS", _) => keys(store),
+        ("PUT", Some(payload)) => put(store, payload.as_str()),
+        ("DELETE", Some(key)) => {
+            match store.delete(key.as_str()) {
+                Ok(_) => Reply::Success(None),
+                Err(e) => {
+                    warn!("failed to delete metadata"; "key" => key, "error" => e.to_string());
+                    Reply::Failure
+                }
+            }
+        }
+        _ => Reply::Failure,
+    };
+    let body = match reply {
+        Reply::Success(Some(value)) => format!("{} SUCCESS {}", id, base64::encode(value.as_bytes())),
+        Reply::Success(None) => format!("{} SUCCESS", id),
+        Reply::NotFound => format!("{} NOTFOUND", id),
+        Reply::Failure => format!("{} FAILURE", id),
+    };
+    format!("V2 {} {:08x} {}\n", body.len(), crc32(body.as_bytes()), body)
+}
+
+/// Splits a V2 frame into request id, code and payload, the length and
+/// checksum have to match the body
+fn parse_v2(line: &str) -> Result<(&str, &str, Option<&str>), Box<Error>> {
+    let mut parts = line.splitn(4, ' ');
+    parts.next();
+    let len: usize = parts.next().unwrap_or("").parse()?;
+    let crc = u32::from_str_radix(parts.next().unwrap_or(""), 16)?;
+    let body = parts.next().unwrap_or("");
+    if body.len() != len || crc32(body.as_bytes()) != crc {
+        return Err(GenericError::bx("invalid length or checksum"));
+    }
+    let mut body = body.splitn(3, ' ');
+    match (body.next(), body.next()) {
+        (Some(id), Some(code)) => Ok((id, code, body.next())),
+        _ => Err(GenericError::bx("missing request id or code")),
+    }
+}
+
+fn get(store: &Store, key: &str) -> Reply {
+    match store.get(key) {
+        Ok(Some(value)) => Reply::Success(Some(value)),
+        Ok(None) => Reply::NotFound,
+        Err(e) => {
+            warn!("failed to read metadata"; "key" => key, "error" => e.to_string());
+            Reply::Failure
+        }
+    }
+}
+
+fn keys(store: &Store) -> Reply {
+    match store.keys() {
+        Ok(keys) => Reply::Success(Some(keys.join("\n"))),
+        Err(e) => {
+            warn!("failed to list metadata"; "error" => e.to_string());
+            Reply::Failure
+        }
+    }
+}
+
+/// The decoded payload of a PUT is `<base64 key> <base64 value>`
+fn put(store: &mut Store, payload: &str) -> Reply {
+    let decode = |s: &str| {
+        base64::decode(s).ok().and_then(|s| String::from_utf8(s).ok())
+    };
+    let mut parts = payload.splitn(2, ' ');
+    let key = parts.next().and_then(&decode);
+    let value = parts.next().and_then(&decode);
+    match (key, value) {
+        (Some(key), Some(value)) => {
+            match store.put(key.as_str(), value.as_str()) {
+                Ok(_) => Reply::Success(None),
+                Err(e) => {
+                    warn!("failed to write metadata"; "key" => key, "error" => e.to_string());
+                    Reply::Failure
+                }
+            }
+        }
+        _ => Reply::Failure,
+    }
+}
+
+/// CRC-32 as used by zlib
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use metadata::protocol::*;
+
+    impl Store for BTreeMap<String, String> {
+        fn get(&self, key: &str) -> Result<Option<String>, Box<Error>> {
+            Ok(BTreeMap::get(self, key).cloned())
+        }
+        fn keys(&self) -> Result<Vec<String>, Box<Error>> {
+            Ok(BTreeMap::keys(self).cloned().collect())
+        }
+        fn put(&mut self, key: &str, value: &str) -> Result<(), Box<Error>> {
+            self.insert(String::from(key), String::from(value));
+            Ok(())
+        }
+        fn delete(&mut self, key: &str) -> Result<(), Box<Error>> {
+            self.remove(key);
+            Ok(())
+        }
+    }
+
+    fn frame(body: &str) -> String {
+        format!("V2 {} {:08x} {}\n", body.len(), crc32(body.as_bytes()), body)
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn v1() {
+        let mut store = BTreeMap::new();
+        store.insert(String::from("motd"), String::from("hello\n.dot"));
+        assert_eq!("SUCCESS\nhello\n..dot\n.\n", handle(&mut store, "GET motd\n"));
+        assert_eq!("NOTFOUND\n", handle(&mut store, "GET missing\n"));
+        assert_eq!("SUCCESS\nmotd\n.\n", handle(&mut store, "KEYS\n"));
+        assert_eq!("invalid command\n", handle(&mut store, "PUT motd\n"));
+    }
+
+    #[test]
+    fn v2() {
+        let mut store = BTreeMap::new();
+        assert_eq!("V2_OK\n", handle(&mut store, "NEGOTIATE V2\n"));
+        let put = format!("{} {}", base64::encode(b"motd"), base64::encode(b"hi"));
+        let put = format!("dc4fae17 PUT {}", base64::encode(put.as_bytes()));
+        assert_eq!(frame("dc4fae17 SUCCESS"), handle(&mut store, frame(put.as_str()).as_str()));
+        let get = |id: &str| frame(format!("{} GET {}", id, base64::encode(b"motd")).as_str());
+        assert_eq!(
+            frame(format!("dc4fae18 SUCCESS {}", base64::encode(b"hi")).as_str()),
+            handle(&mut store, get("dc4fae18").as_str())
+        );
+        let delete = format!("dc4fae19 DELETE {}", base64::encode(b"motd"));
+        assert_eq!(frame("dc4fae19 SUCCESS"), handle(&mut store, frame(delete.as_str()).as_str()));
+        assert_eq!(frame("dc4fae1a NOTFOUND"), handle(&mut store, get("dc4fae1a").as_str()));
+        assert_eq!("invalid command\n", handle(&mut store, "V2 3 00000000 abc\n"));
+    }
+}
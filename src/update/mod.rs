@@ -1,8 +1,11 @@
 //! Update for a jail
 use jail_config::{JailConfig, NIC};
+use config::Config;
+use errors::{ValidationError, ValidationErrors};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::Read;
-use serde_json;
+use serde_json::{self, Value};
 use uuid::Uuid;
 
 
@@ -36,6 +39,8 @@ struct NICUpdate {
     ip: Option<String>,
     gateway: Option<String>,
     netmask: Option<String>,
+    ips: Option<Vec<String>>,
+    gateways: Option<Vec<String>>,
     vlan: Option<u16>,
     primary: Option<bool>,
     mtu: Option<u32>,
@@ -51,6 +56,8 @@ impl NICUpdate {
             ip: None,
             gateway: None,
             netmask: None,
+            ips: None,
+            gateways: None,
             vlan: None,
             primary: None,
             mtu: None,
@@ -76,7 +83,13 @@ impl NICUpdate {
                        mtu,
                        network_uuid
         );
-        return nic;
+        if self.ips.is_some() || self.gateways.is_some() {
+            update!(self, nic; ips, gateways);
+            nic.ips_to_legacy();
+        } else if self.ip.is_some() || self.netmask.is_some() || self.gateway.is_some() {
+            nic.legacy_to_ips();
+        }
+        nic
     }
 }
 
@@ -93,8 +106,8 @@ pub struct JailUpdate {
     max_physical_memory: Option<u64>,
     /// mac cpu usage 100 = 1 core (pcpu)
     cpu_cap: Option<u64>,
-    /// max quota (zfs quota)
-    //    quota: u64,
+    /// max quota in GB (zfs quota)
+    quota: Option<u64>,
     /// SysV shared memory size, in bytes (shmsize)
     max_shm_memory: Option<u64>,
 
@@ -104,12 +117,19 @@ pub struct JailUpdate {
     /// maximum number of porocesses (maxproc)
     max_lwps: Option<u64>,
 
+    /// protect the delegated dataset and the jail root from being
+    /// destroyed, clearing them releases the protection
+    indestructible_delegated: Option<bool>,
+    indestructible_zoneroot: Option<bool>,
+
     // Metadata fields w/o effect on vmadm at the moment
     archive_on_delete: Option<bool>,
     billing_id: Option<Uuid>,
     do_not_inventory: Option<bool>,
-    // Currently has no effect
     dns_domain: Option<String>,
+    resolvers: Option<Vec<String>>,
+    maintain_resolvers: Option<bool>,
+    user_script_every_boot: Option<bool>,
 
     owner_uuid: Option<Uuid>,
     package_name: Option<String>,
@@ -120,7 +140,26 @@ pub struct JailUpdate {
     remove_nics: Vec<String>,
     #[serde(default = "empty_nic_update")]
     update_nics: Vec<NICUpdate>,
-
+    /// customer metadata to add or replace
+    set_customer_metadata: Option<BTreeMap<String, String>>,
+    /// keys of customer metadata to remove
+    #[serde(default = "empty_macs")]
+    remove_customer_metadata: Vec<String>,
+    /// internal metadata to add or replace
+    set_internal_metadata: Option<BTreeMap<String, String>>,
+    /// keys of internal metadata to remove
+    #[serde(default = "empty_macs")]
+    remove_internal_metadata: Vec<String>,
+    /// routes to add or replace
+    set_routes: Option<BTreeMap<String, String>>,
+    /// destinations of routes to remove
+    #[serde(default = "empty_macs")]
+    remove_routes: Vec<String>,
+    /// tags to add or replace
+    set_tags: Option<BTreeMap<String, Value>>,
+    /// keys of tags to remove
+    #[serde(default = "empty_macs")]
+    remove_tags: Vec<String>,
 }
 
 impl JailUpdate {
@@ -130,8 +169,55 @@ impl JailUpdate {
         R: Read,
     {
         let update: JailUpdate = serde_json::from_reader(reader)?;
-        return Ok(update);
+        Ok(update)
+    }
+
+    /// Applies the update to `current` and checks the update and the
+    /// resulting config for errors
+    pub fn apply_checked(&self, config: &Config, current: JailConfig) -> Result<JailConfig, Box<Error>> {
+        let mut errors = Vec::new();
+        let exists = |mac: &String| current.nics.iter().any(|nic| nic.mac == *mac);
+        for (i, mac) in self.remove_nics.iter().enumerate() {
+            if !exists(mac) {
+                errors.push(ValidationError::new(format!("remove_nics[{}]", i).as_str(), "Unknown mac"))
+            }
+        }
+        for (i, nic) in self.add_nics.iter().enumerate() {
+            let path = format!("add_nics[{}]", i);
+            if exists(&nic.mac) && !self.remove_nics.contains(&nic.mac) {
+                errors.push(ValidationError::new(format!("{}.mac", path).as_str(), "Duplicate mac"))
+            }
+            let mut nic = nic.clone();
+            nic.normalize();
+            errors.append(&mut nic.input_errors(config, path.as_str()));
+        }
+        for (i, update) in self.update_nics.iter().enumerate() {
+            let path = format!("update_nics[{}]", i);
+            match current.nics.iter().find(|nic| nic.mac == update.mac) {
+                Some(nic) if !self.remove_nics.contains(&update.mac) => {
+                    let nic = update.apply(nic.clone());
+                    errors.append(&mut nic.input_errors(config, path.as_str()))
+                }
+                _ => errors.push(ValidationError::new(format!("{}.mac", path).as_str(), "Unknown mac")),
+            }
+        }
+        for (i, dest) in self.remove_routes.iter().enumerate() {
+            if !current.routes.contains_key(dest) {
+                errors.push(ValidationError::new(format!("remove_routes[{}]", i).as_str(), "Unknown route"))
+            }
+        }
+        if self.update_nics.iter().filter(|u| u.primary == Some(true)).count() > 1 {
+            errors.push(ValidationError::new("update_nics", "More than one primary nic"))
+        }
+        let result = self.apply(current);
+        errors.append(&mut result.field_errors());
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(ValidationErrors::bx(errors))
+        }
     }
+
     #[cfg(test)]
     pub fn empty() -> Self {
         JailUpdate {
@@ -140,19 +226,33 @@ impl JailUpdate {
             autoboot: None,
             max_physical_memory: None,
             cpu_cap: None,
+            quota: None,
             max_shm_memory: None,
             max_locked_memory: None,
             max_lwps: None,
             archive_on_delete: None,
+            indestructible_delegated: None,
+            indestructible_zoneroot: None,
             billing_id: None,
             do_not_inventory: None,
             dns_domain: None,
+            resolvers: None,
+            maintain_resolvers: None,
+            user_script_every_boot: None,
             owner_uuid: None,
             package_name: None,
             package_version: None,
             add_nics: vec![],
             remove_nics: vec![],
             update_nics: vec![],
+            set_customer_metadata: None,
+            remove_customer_metadata: vec![],
+            set_internal_metadata: None,
+            remove_internal_metadata: vec![],
+            set_routes: None,
+            remove_routes: vec![],
+            set_tags: None,
+            remove_tags: vec![],
 
         }
     }
@@ -164,13 +264,19 @@ impl JailUpdate {
                 hostname,
                 max_physical_memory,
                 cpu_cap,
+                quota,
                 max_lwps,
-                dns_domain
+                dns_domain,
+                resolvers,
+                maintain_resolvers,
+                user_script_every_boot
         );
         update_option!(self, c;
             max_shm_memory,
             max_locked_memory,
             archive_on_delete,
+            indestructible_delegated,
+            indestructible_zoneroot,
             billing_id,
             do_not_inventory,
             owner_uuid,
@@ -179,9 +285,24 @@ impl JailUpdate {
         );
 
 
+        set_keys(&mut c.routes, &self.set_routes, &self.remove_routes);
+        set_keys(&mut c.tags, &self.set_tags, &self.remove_tags);
+        set_keys(
+            &mut c.customer_metadata,
+            &self.set_customer_metadata,
+            &self.remove_customer_metadata,
+        );
+        set_keys(
+            &mut c.internal_metadata,
+            &self.set_internal_metadata,
+            &self.remove_internal_metadata,
+        );
+
         c.nics.retain(|nic| !self.remove_nics.contains(&nic.mac));
         for nic in self.add_nics.iter() {
-            c.nics.push(nic.clone());
+            let mut nic = nic.clone();
+            nic.normalize();
+            c.nics.push(nic);
         }
         for update in self.update_nics.iter() {
 
@@ -199,11 +320,72 @@ impl JailUpdate {
 
         }
 
-        return c;
+        c
     }
 }
 
 
+/// Field level differences between two configs, one line per changed,
+/// added (`+`) or removed (`-`) field
+pub fn diff(old: &JailConfig, new: &JailConfig) -> Result<Vec<String>, Box<Error>> {
+    let mut res = Vec::new();
+    diff_values(&serde_json::to_value(old)?, &serde_json::to_value(new)?, "", &mut res);
+    Ok(res)
+}
+
+fn diff_values(old: &Value, new: &Value, path: &str, res: &mut Vec<String>) {
+    let join = |key: &str| if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    };
+    match (old, new) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a.iter() {
+                match b.get(key) {
+                    Some(other) => diff_values(value, other, join(key).as_str(), res),
+                    None => res.push(format!("- {}: {}", join(key), value)),
+                }
+            }
+            for (key, value) in b.iter().filter(|&(k, _)| !a.contains_key(k)) {
+                res.push(format!("+ {}: {}", join(key), value));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for (i, value) in a.iter().enumerate() {
+                let element = format!("{}[{}]", path, i);
+                match b.get(i) {
+                    Some(other) => diff_values(value, other, element.as_str(), res),
+                    None => res.push(format!("- {}: {}", element, value)),
+                }
+            }
+            for (i, value) in b.iter().enumerate().skip(a.len()) {
+                res.push(format!("+ {}[{}]: {}", path, i, value));
+            }
+        }
+        _ => {
+            if old != new {
+                res.push(format!("~ {}: {} -> {}", path, old, new));
+            }
+        }
+    }
+}
+
+/// Inserts the `set` entries into `map` and removes the keys in `remove`
+fn set_keys<V: Clone>(
+    map: &mut BTreeMap<String, V>,
+    set: &Option<BTreeMap<String, V>>,
+    remove: &[String],
+) {
+    if let Some(ref set) = *set {
+        for (key, value) in set.iter() {
+            map.insert(key.clone(), value.clone());
+        }
+    }
+    for key in remove.iter() {
+        map.remove(key);
+    }
+}
 
 fn empty_macs() -> Vec<String> {
     Vec::new()
@@ -222,6 +404,7 @@ fn empty_nics() -> Vec<NIC> {
 #[cfg(test)]
 mod tests {
     use jail_config::JailConfig;
+    use config::Simulated;
     use update::*;
     use uuid::Uuid;
 
@@ -234,6 +417,8 @@ mod tests {
             ip: String::from("192.168.254.254"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![String::from("192.168.254.254/24")],
+            gateways: vec![String::from("192.168.254.1")],
             primary: true,
             mtu: None,
             network_uuid: None
@@ -248,6 +433,8 @@ mod tests {
             ip: String::from("192.168.254.253"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![String::from("192.168.254.253/24")],
+            gateways: vec![String::from("192.168.254.1")],
             primary: false,
             mtu: None,
             network_uuid: None
@@ -262,6 +449,8 @@ mod tests {
             ip: String::from("192.168.254.252"),
             netmask: String::from("255.255.255.0"),
             gateway: String::from("192.168.254.1"),
+            ips: vec![String::from("192.168.254.252/24")],
+            gateways: vec![String::from("192.168.254.1")],
             primary: false,
             mtu: None,
             network_uuid: None
@@ -282,16 +471,26 @@ mod tests {
             max_shm_memory: None,
             max_locked_memory: None,
             nics: vec![nic00(), nic01()],
+            routes: BTreeMap::new(),
             max_lwps: 2000,
             archive_on_delete: None,
             billing_id: None,
             do_not_inventory: None,
             dns_domain: String::from("local"),
+            resolvers: vec![],
+            maintain_resolvers: false,
             indestructible_delegated: None,
             indestructible_zoneroot: None,
             owner_uuid: None,
             package_name: None,
             package_version: None,
+            customer_metadata: BTreeMap::new(),
+            internal_metadata: BTreeMap::new(),
+            internal_metadata_namespaces: vec![],
+            delegate_dataset: false,
+            filesystems: vec![],
+            tags: BTreeMap::new(),
+            user_script_every_boot: false,
         }
     }
 
@@ -326,10 +525,10 @@ mod tests {
     #[test]
     fn autoboot() {
         let conf = conf();
-        assert_eq!(true, conf.autoboot);
+        assert!(conf.autoboot);
         let mut update = JailUpdate::empty();
         update.autoboot = Some(false);
-        assert_eq!(false, update.apply(conf).autoboot);
+        assert!(!update.apply(conf).autoboot);
     }
     #[test]
     fn max_physical_memory() {
@@ -340,6 +539,14 @@ mod tests {
         assert_eq!(42, update.apply(conf).max_physical_memory);
     }
     #[test]
+    fn quota() {
+        let conf = conf();
+        assert_eq!(5, conf.quota);
+        let mut update = JailUpdate::empty();
+        update.quota = Some(42);
+        assert_eq!(42, update.apply(conf).quota);
+    }
+    #[test]
     fn max_locked_memory() {
         let conf = conf();
         assert_eq!(None, conf.max_locked_memory);
@@ -361,7 +568,7 @@ mod tests {
         assert_eq!(None, conf.archive_on_delete);
         let mut update = JailUpdate::empty();
         update.archive_on_delete = Some(true);
-        assert_eq!(true, update.apply(conf).archive_on_delete.unwrap());
+        assert!(update.apply(conf).archive_on_delete.unwrap());
     }
     #[test]
     fn billing_id() {
@@ -377,8 +584,36 @@ mod tests {
         assert_eq!(None, conf.do_not_inventory);
         let mut update = JailUpdate::empty();
         update.do_not_inventory = Some(true);
-        assert_eq!(true, update.apply(conf).do_not_inventory.unwrap());
+        assert!(update.apply(conf).do_not_inventory.unwrap());
     }
+    #[test]
+    fn metadata() {
+        let mut conf = conf();
+        conf.customer_metadata.insert(String::from("user-script"), String::from("old"));
+        conf.customer_metadata.insert(String::from("gone"), String::from("bye"));
+        let mut update = JailUpdate::empty();
+        let mut set = BTreeMap::new();
+        set.insert(String::from("user-script"), String::from("new"));
+        update.set_customer_metadata = Some(set.clone());
+        update.remove_customer_metadata = vec![String::from("gone")];
+        update.set_internal_metadata = Some(set.clone());
+        let conf = update.apply(conf);
+        assert_eq!(set, conf.customer_metadata);
+        assert_eq!(set, conf.internal_metadata);
+    }
+
+    #[test]
+    fn resolvers() {
+        let conf = conf();
+        let mut update = JailUpdate::empty();
+        let resolvers = vec![String::from("192.168.254.53")];
+        update.resolvers = Some(resolvers.clone());
+        update.maintain_resolvers = Some(true);
+        let conf = update.apply(conf);
+        assert_eq!(resolvers, conf.resolvers);
+        assert!(conf.maintain_resolvers);
+    }
+
     #[test]
     fn dns_domain() {
         let conf = conf();
@@ -437,8 +672,128 @@ mod tests {
         update.update_nics = vec![nic_update];
         let conf1 = update.apply(conf.clone());
 
-        assert_eq!(false, conf1.nics[0].primary);
-        assert_eq!(true, conf1.nics[1].primary);
+        assert!(!conf1.nics[0].primary);
+        assert!(conf1.nics[1].primary);
+    }
+
+    #[test]
+    fn checked_valid() {
+        let mut update = JailUpdate::empty();
+        let mut nic_update = NICUpdate::empty(nic01().mac.clone());
+        nic_update.ip = Some(String::from("192.168.254.200"));
+        update.update_nics = vec![nic_update];
+        update.remove_nics = vec![nic00().mac.clone()];
+        let (config, _) = Simulated::new();
+        assert!(update.apply_checked(&config, conf()).is_ok());
+    }
+
+    #[test]
+    fn checked_errors() {
+        let mut update = JailUpdate::empty();
+        update.hostname = Some(String::from("not a hostname"));
+        update.remove_nics = vec![String::from("00:00:00:00:00:42")];
+        let mut added = nic02();
+        added.primary = true;
+        added.nic_tag = String::from("missing");
+        update.add_nics = vec![added];
+        let mut nic_update = NICUpdate::empty(nic01().mac.clone());
+        nic_update.gateway = Some(String::from("192.168.254.1"));
+        let mut bad_update = NICUpdate::empty(nic01().mac.clone());
+        bad_update.ip = Some(String::from("192.168.254.300"));
+        let unknown_update = NICUpdate::empty(String::from("00:00:00:00:00:42"));
+        update.update_nics = vec![nic_update, bad_update, unknown_update];
+        let (config, _) = Simulated::new();
+        let error = update.apply_checked(&config, conf()).unwrap_err().to_string();
+        let mut lines: Vec<&str> = error.lines().skip(1).map(|l| l.trim()).collect();
+        lines.sort();
+        assert_eq!(
+            vec![
+                "add_nics[0].nic_tag: Unknown nic_tag",
+                "hostname: Invalid hostname",
+                "nics: More than one primary nic",
+                "remove_nics[0]: Unknown mac",
+                "update_nics[1].ip: Invalid ip",
+                "update_nics[2].mac: Unknown mac",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn routes() {
+        let mut conf = conf();
+        conf.routes.insert(String::from("10.0.0.0/8"), String::from("192.168.254.1"));
+        conf.routes.insert(String::from("10.1.0.1"), String::from("nics[1]"));
+        let mut update = JailUpdate::empty();
+        let mut routes = BTreeMap::new();
+        routes.insert(String::from("10.0.0.0/8"), String::from("192.168.254.2"));
+        update.set_routes = Some(routes);
+        update.remove_routes = vec![String::from("10.1.0.1"), String::from("10.2.0.1")];
+        let (config, _) = Simulated::new();
+        let error = update.apply_checked(&config, conf.clone()).unwrap_err().to_string();
+        assert!(error.contains("remove_routes[1]: Unknown route"));
+        update.remove_routes.pop();
+        let routes = update.apply(conf).routes;
+        assert_eq!(1, routes.len());
+        assert_eq!(Some(&String::from("192.168.254.2")), routes.get("10.0.0.0/8"));
+    }
+
+    #[test]
+    fn indestructible() {
+        let mut conf = conf();
+        conf.indestructible_zoneroot = Some(true);
+        let mut update = JailUpdate::empty();
+        update.indestructible_zoneroot = Some(false);
+        update.indestructible_delegated = Some(true);
+        let conf = update.apply(conf);
+        assert_eq!(Some(false), conf.indestructible_zoneroot);
+        assert_eq!(Some(true), conf.indestructible_delegated);
+    }
+
+    #[test]
+    fn tags() {
+        let mut conf = conf();
+        conf.tags.insert(String::from("role"), Value::from("db"));
+        conf.tags.insert(String::from("gone"), Value::from(true));
+        let mut update = JailUpdate::empty();
+        let mut tags = BTreeMap::new();
+        tags.insert(String::from("shard"), Value::from(3));
+        tags.insert(String::from("customer"), Value::from(vec!["a", "b"]));
+        update.set_tags = Some(tags);
+        update.remove_tags = vec![String::from("gone")];
+        let (config, _) = Simulated::new();
+        let error = update.apply_checked(&config, conf.clone()).unwrap_err().to_string();
+        assert!(error.contains("tags.customer: Tags must be strings, numbers or booleans"));
+        update.set_tags.as_mut().unwrap().remove("customer");
+        let tags = update.apply(conf).tags;
+        assert_eq!(vec!["role", "shard"], tags.keys().collect::<Vec<_>>());
+        assert_eq!(Some(&Value::from(3)), tags.get("shard"));
+    }
+
+    #[test]
+    fn diff_configs() {
+        let old = conf();
+        let mut update = JailUpdate::empty();
+        update.hostname = Some(String::from("changed"));
+        update.remove_nics = vec![nic01().mac.clone()];
+        let mut nic_update = NICUpdate::empty(nic00().mac.clone());
+        nic_update.mtu = Some(9000);
+        nic_update.ip = Some(String::from("192.168.254.200"));
+        update.update_nics = vec![nic_update];
+        let new = update.apply(old.clone());
+        let lines = diff(&old, &new).unwrap();
+        assert_eq!(
+            vec![
+                r#"~ hostname: "test-hostname" -> "changed""#,
+                r#"~ nics[0].ip: "192.168.254.254" -> "192.168.254.200""#,
+                r#"~ nics[0].ips[0]: "192.168.254.254/24" -> "192.168.254.200/24""#,
+                "+ nics[0].mtu: 9000",
+            ],
+            lines[..4].to_vec()
+        );
+        assert_eq!(5, lines.len());
+        assert!(lines[4].starts_with("- nics[1]: {"));
+        assert!(diff(&old, &old).unwrap().is_empty());
     }
 
     // nic update tests
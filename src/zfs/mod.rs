@@ -2,20 +2,25 @@
 //! Wrapper around zfs commands
 
 use std::error::Error;
-use std::process::{Command, Stdio};
 use errors::GenericError;
+use config::Config;
 use std::io::Read;
-use std::io::copy;
 
 
 #[derive(Debug)]
+#[allow(dead_code)]
 /// Basic information about a ZFS dataset
 pub struct ZFSEntry {
-    name: String,
-    used: u64,
-    avail: u64,
-    refer: u64,
-    mountpoint: String,
+    /// name of the dataset
+    pub name: String,
+    /// bytes used by the dataset and its children
+    pub used: u64,
+    /// bytes available to the dataset
+    pub avail: u64,
+    /// bytes referenced by the dataset
+    pub refer: u64,
+    /// where the dataset is mounted
+    pub mountpoint: String,
 }
 
 // /// reads the zfs datasets in a pool
@@ -37,49 +42,42 @@ pub struct ZFSEntry {
 // }
 
 
-pub fn receive<R>(dataset: &str, mut reader: &mut R) -> Result<i32, Box<Error>>
+/// receives a zfs stream into a dataset
+pub fn receive<R>(config: &Config, dataset: &str, reader: &mut R) -> Result<i32, Box<Error>>
     where
     R: Read,
 {
-    let mut rec = Command::new("zfs")
-        .args(&["receive", dataset])
-        .stdin(Stdio::piped())
-        .spawn().unwrap();
-    let mut stdin = rec.stdin.take().unwrap();
-    copy(&mut reader, &mut stdin)?;
-    Ok(0)
+    debug!("Receiving ZFS dataset"; "dataset" => dataset);
+    let output = config.executor.pipe("zfs", &["receive", dataset], reader)?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to receive dataset"))
+    }
 }
 
 /// checks weather a dataset exists or not
-pub fn is_present(dataset: &str) -> bool {
-    get(dataset).is_ok()
+pub fn is_present(config: &Config, dataset: &str) -> bool {
+    get(config, dataset).is_ok()
 }
 
 /// reads the zfs datasets in a pool
-fn get(dataset: &str) -> Result<ZFSEntry, Box<Error>> {
+pub fn get(config: &Config, dataset: &str) -> Result<ZFSEntry, Box<Error>> {
     debug!("Reading ZFS dataset"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["list", "-p", "-H", dataset])
-        .output()
-        .expect("zfs list failed");
-    if output.status.success() {
-        let reply = String::from_utf8_lossy(&output.stdout).to_string();
-        deconstruct_entry(reply.as_str())
+    let output = config.executor.run("zfs", &["list", "-p", "-H", dataset])?;
+    if output.success() {
+        deconstruct_entry(output.stdout.trim())
     } else {
         Err(GenericError::bx("Failed to get dataset"))
     }
 }
 
 /// reads the zfs datasets in a pool
-pub fn origin(dataset: &str) -> Result<String, Box<Error>> {
+pub fn origin(config: &Config, dataset: &str) -> Result<String, Box<Error>> {
     debug!("Fetching ZFS origin"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["get", "-p", "-H", "origin", dataset])
-        .output()
-        .expect("zfs list failed");
-    if output.status.success() {
-        let out = String::from_utf8_lossy(&output.stdout).to_string();
-        let mut reply = out.split('\t');
+    let output = config.executor.run("zfs", &["get", "-p", "-H", "origin", dataset])?;
+    if output.success() {
+        let mut reply = output.stdout.split('\t');
         reply.next().ok_or_else(
             || GenericError::bx("NAME field missing"),
         )?;
@@ -95,32 +93,79 @@ pub fn origin(dataset: &str) -> Result<String, Box<Error>> {
     }
 }
 
-// /// create a zfs datasets in a pool
-// pub fn create(dataset: &str) -> Result<i32, Box<Error>> {
-//     debug!("Creating ZFS dataset"; "dataset" => dataset);
-//     let output = Command::new("zfs")
-//         .args(&["create", dataset])
-//         .output()
-//         .expect("zfs create failed");
-//     if output.status.success() {
-//         Ok(0)
-//     } else {
-//         Err(GenericError::bx("Failed create dataset"))
-//     }
-// }
+/// reads a property of a dataset, numeric values are in bytes
+pub fn get_property(config: &Config, dataset: &str, property: &str) -> Result<String, Box<Error>> {
+    debug!("Reading ZFS property"; "dataset" => dataset, "property" => property);
+    let output = config.executor.run(
+        "zfs",
+        &["get", "-p", "-H", "-o", "value", property, dataset],
+    )?;
+    if output.success() {
+        Ok(String::from(output.stdout.trim()))
+    } else {
+        Err(GenericError::bx("Failed to get property"))
+    }
+}
+
+/// sets a property of a dataset
+pub fn set_property(
+    config: &Config,
+    dataset: &str,
+    property: &str,
+    value: &str,
+) -> Result<i32, Box<Error>> {
+    let mut assignment = String::from(property);
+    assignment.push('=');
+    assignment.push_str(value);
+    debug!("Setting ZFS property"; "dataset" => dataset, "property" => property,
+           "value" => value);
+    let output = config.executor.run("zfs", &["set", assignment.as_str(), dataset])?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to set property"))
+    }
+}
+
+/// creates a dataset with the given `property=value` settings without
+/// mounting it
+pub fn create(config: &Config, dataset: &str, properties: &[&str]) -> Result<i32, Box<Error>> {
+    debug!("Creating ZFS dataset"; "dataset" => dataset, "properties" => properties.join(" "));
+    let mut args = vec!["create", "-u"];
+    for property in properties {
+        args.push("-o");
+        args.push(property);
+    }
+    args.push(dataset);
+    let output = config.executor.run("zfs", &args)?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed create dataset"))
+    }
+}
+
+/// attaches a dataset with `jailed=on` to a running jail
+pub fn jail(config: &Config, jail: &str, dataset: &str) -> Result<i32, Box<Error>> {
+    debug!("Attaching ZFS dataset to jail"; "dataset" => dataset, "jail" => jail);
+    let output = config.executor.run("zfs", &["jail", jail, dataset])?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to attach dataset to jail"))
+    }
+}
 
 /// create a zfs snapshot of a dataset
-pub fn snapshot(dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
+pub fn snapshot(config: &Config, dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
     let mut snap = String::from(dataset);
     snap.push('@');
     snap.push_str(snapshot);
     let args = vec!["snapshot", snap.as_str()];
     debug!("Creating ZFS snapshot"; "dataset" => dataset, "snapshot" => snapshot,
     "args" => args.clone().join(" "));
-    let output = Command::new("zfs").args(args).output().expect(
-        "zfs snapshot failed",
-    );
-    if output.status.success() {
+    let output = config.executor.run("zfs", &args)?;
+    if output.success() {
         Ok(snap.clone())
     } else {
         Err(GenericError::bx("Failed create snapshot"))
@@ -128,27 +173,44 @@ pub fn snapshot(dataset: &str, snapshot: &str) -> Result<String, Box<Error>> {
 }
 
 /// clones a zfs snapshot
-pub fn clone(snapshot: &str, dataset: &str) -> Result<i32, Box<Error>> {
+pub fn clone(config: &Config, snapshot: &str, dataset: &str) -> Result<i32, Box<Error>> {
     debug!("Cloning ZFS snapshot"; "snapshot" => snapshot, "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["clone", snapshot, dataset])
-        .output()
-        .expect("zfs clone failed");
-    if output.status.success() {
+    let output = config.executor.run("zfs", &["clone", snapshot, dataset])?;
+    if output.success() {
         Ok(0)
     } else {
         Err(GenericError::bx("Failed to clone dataset"))
     }
 }
 
+/// places a hold with the given tag on a snapshot, held snapshots can
+/// not be destroyed
+pub fn hold(config: &Config, tag: &str, snapshot: &str) -> Result<i32, Box<Error>> {
+    debug!("Holding ZFS snapshot"; "snapshot" => snapshot, "tag" => tag);
+    let output = config.executor.run("zfs", &["hold", tag, snapshot])?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to hold snapshot"))
+    }
+}
+
+/// releases a hold placed with `hold`
+pub fn release(config: &Config, tag: &str, snapshot: &str) -> Result<i32, Box<Error>> {
+    debug!("Releasing ZFS snapshot"; "snapshot" => snapshot, "tag" => tag);
+    let output = config.executor.run("zfs", &["release", tag, snapshot])?;
+    if output.success() {
+        Ok(0)
+    } else {
+        Err(GenericError::bx("Failed to release snapshot"))
+    }
+}
+
 /// destroy the zfs datasets in a pool
-pub fn destroy(dataset: &str) -> Result<i32, Box<Error>> {
+pub fn destroy(config: &Config, dataset: &str) -> Result<i32, Box<Error>> {
     debug!("deleteing ZFS dataset"; "dataset" => dataset);
-    let output = Command::new("zfs")
-        .args(&["destroy", dataset])
-        .output()
-        .expect("zfs destroy failed");
-    if output.status.success() {
+    let output = config.executor.run("zfs", &["destroy", dataset])?;
+    if output.success() {
         Ok(0)
     } else {
         Err(GenericError::bx("Failed destroy dataset"))